@@ -0,0 +1,463 @@
+use crate::tls::crypto_provider;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use log::{error, info};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+pub const DEFAULT_ACME_CA: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const DEFAULT_ACME_STORAGE: &str = "acme";
+pub const DEFAULT_HTTP_CHALLENGE_PORT: u16 = 80;
+/// Protocol the CA negotiates on TLS-ALPN-01 validation connections (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+pub const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// How often a certificate that isn't due yet is looked at again
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Wait after a failed order, doubled on each failure in a row up to the maximum: CAs limit
+/// failed validations (Let's Encrypt to 5 per hostname and hour)
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(12 * 60 * 60);
+const ORDER_TIMEOUT: Duration = Duration::from_secs(120);
+
+type AcmeResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How the CA checks that we control a host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcmeChallenge {
+    /// A token served over plain HTTP on port 80
+    Http01,
+    /// A special certificate presented on port 443
+    TlsAlpn01,
+}
+
+impl AcmeChallenge {
+    pub fn from_arg(arg: &str) -> Option<AcmeChallenge> {
+        match arg {
+            "http-01" => Some(AcmeChallenge::Http01),
+            "tls-alpn-01" => Some(AcmeChallenge::TlsAlpn01),
+            _ => None,
+        }
+    }
+
+    fn challenge_type(self) -> ChallengeType {
+        match self {
+            AcmeChallenge::Http01 => ChallengeType::Http01,
+            AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        }
+    }
+}
+
+/// Options block of a `tls "auto"` directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcmeOptions {
+    /// Contact address registered with the CA
+    pub email: Option<String>,
+    /// Directory URL of the CA
+    pub ca: String,
+    /// PEM root the CA's own HTTPS endpoint is verified against, for test CAs like Pebble
+    pub ca_root: Option<String>,
+    /// Directory the account, certificates and keys are kept in
+    pub storage: String,
+    pub challenge: AcmeChallenge,
+    /// Port HTTP-01 challenges are answered on
+    pub http_port: u16,
+}
+
+impl Default for AcmeOptions {
+    fn default() -> Self {
+        AcmeOptions {
+            email: None,
+            ca: DEFAULT_ACME_CA.to_string(),
+            ca_root: None,
+            storage: DEFAULT_ACME_STORAGE.to_string(),
+            challenge: AcmeChallenge::Http01,
+            http_port: DEFAULT_HTTP_CHALLENGE_PORT,
+        }
+    }
+}
+
+/// Certificates obtained through ACME and the answers to pending challenges, shared by all
+/// listeners so any of them can take part in a validation.
+#[derive(Debug, Default)]
+pub struct AcmeState {
+    /// Host -> Certificate
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// Token -> Key authorization
+    http_challenges: RwLock<HashMap<String, String>>,
+    /// Host -> TLS-ALPN-01 certificate
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    /// Hosts sharing a CA also share its account, only one of them creates it
+    account: tokio::sync::Mutex<()>,
+}
+
+impl AcmeState {
+    pub fn certificate(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs.read().unwrap().get(host).cloned()
+    }
+
+    /// The certificate of the first host by name, for clients that don't say which they want
+    pub fn any_certificate(&self) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        certs.keys().min().and_then(|host| certs.get(host)).cloned()
+    }
+
+    pub fn alpn_challenge(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        self.alpn_challenges.read().unwrap().get(host).cloned()
+    }
+
+    /// The key authorization to answer a request for `path` with, if it is a pending
+    /// HTTP-01 challenge
+    pub fn http_challenge(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PATH)?;
+        self.http_challenges.read().unwrap().get(token).cloned()
+    }
+}
+
+/// Serves the stored certificate of `host` if there is one, then obtains a new one from the
+/// CA whenever it is due: when missing or a third of its lifetime is left.
+pub fn spawn_renewal(host: String, options: AcmeOptions, state: Arc<AcmeState>) {
+    tokio::spawn(async move {
+        let mut renew_at = match load_stored(Path::new(&options.storage), &host).await {
+            Ok(Some((certified, renew_at))) => {
+                state
+                    .certs
+                    .write()
+                    .unwrap()
+                    .insert(host.clone(), Arc::new(certified));
+                renew_at
+            }
+            Ok(None) => SystemTime::now(),
+            Err(err) => {
+                error!("Stored certificate for {} is unusable: {}", host, err);
+                SystemTime::now()
+            }
+        };
+        let mut retry_delay = RETRY_DELAY;
+        loop {
+            if let Ok(wait) = renew_at.duration_since(SystemTime::now()) {
+                tokio::time::sleep(wait.min(CHECK_INTERVAL)).await;
+                continue;
+            }
+            match obtain(&host, &options, &state).await {
+                Ok(next) => {
+                    info!("Obtained a certificate for {}", host);
+                    renew_at = next;
+                    retry_delay = RETRY_DELAY;
+                }
+                Err(err) => {
+                    error!(
+                        "Failed to obtain a certificate for {}, retrying in {:?}: {}",
+                        host, retry_delay, err
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = next_retry_delay(retry_delay);
+                }
+            }
+        }
+    });
+}
+
+fn next_retry_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RETRY_DELAY)
+}
+
+/// Runs an order for `host` through the CA, stores the certificate and starts serving it.
+/// Returns when it is due for renewal.
+async fn obtain(host: &str, options: &AcmeOptions, state: &AcmeState) -> AcmeResult<SystemTime> {
+    let storage = Path::new(&options.storage);
+    tokio::fs::create_dir_all(storage).await?;
+    let account = {
+        let _creating = state.account.lock().await;
+        account(storage, options).await?
+    };
+
+    let identifiers = [Identifier::Dns(host.to_string())];
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+    let mut tokens = Vec::new();
+    let status = authorize(&mut order, host, options, state, &mut tokens).await;
+    // Answered or not, the challenges are over
+    {
+        let mut http_challenges = state.http_challenges.write().unwrap();
+        for token in tokens {
+            http_challenges.remove(&token);
+        }
+    }
+    state.alpn_challenges.write().unwrap().remove(host);
+    let status = status?;
+    if status != OrderStatus::Ready {
+        return Err(format!("Order for {} is {:?}", host, status).into());
+    }
+
+    let key_pem = order.finalize().await?;
+    let retries = RetryPolicy::new().timeout(ORDER_TIMEOUT);
+    let chain_pem = order.poll_certificate(&retries).await?;
+    let certified = certified_key(&chain_pem, &key_pem)?;
+    let renew_at = renewal_time(&certified.cert[0])?;
+
+    let (cert_path, key_path) = stored_paths(storage, host);
+    write_private(&cert_path, &chain_pem).await?;
+    write_private(&key_path, &key_pem).await?;
+    state
+        .certs
+        .write()
+        .unwrap()
+        .insert(host.to_string(), Arc::new(certified));
+    Ok(renew_at)
+}
+
+/// Sets up the configured challenge of every pending authorization and waits for the CA
+/// to check them.
+async fn authorize(
+    order: &mut Order,
+    host: &str,
+    options: &AcmeOptions,
+    state: &AcmeState,
+    tokens: &mut Vec<String>,
+) -> AcmeResult<OrderStatus> {
+    let mut authorizations = order.authorizations();
+    while let Some(authorization) = authorizations.next().await {
+        let mut authorization = authorization?;
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => return Err(format!("Authorization for {} is {:?}", host, status).into()),
+        }
+        let mut challenge = authorization
+            .challenge(options.challenge.challenge_type())
+            .ok_or_else(|| format!("The CA offers no {:?} challenge", options.challenge))?;
+        let key_authorization = challenge.key_authorization();
+        match options.challenge {
+            AcmeChallenge::Http01 => {
+                tokens.push(challenge.token.clone());
+                state.http_challenges.write().unwrap().insert(
+                    challenge.token.clone(),
+                    key_authorization.as_str().to_string(),
+                );
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certified = alpn_certificate(host, key_authorization.digest().as_ref())?;
+                state
+                    .alpn_challenges
+                    .write()
+                    .unwrap()
+                    .insert(host.to_string(), Arc::new(certified));
+            }
+        }
+        challenge.set_ready().await?;
+    }
+    let retries = RetryPolicy::new().timeout(ORDER_TIMEOUT);
+    Ok(order.poll_ready(&retries).await?)
+}
+
+/// The account with the CA, registered on first use and kept in `storage` afterwards
+async fn account(storage: &Path, options: &AcmeOptions) -> AcmeResult<Account> {
+    let builder = || match &options.ca_root {
+        Some(root) => Account::builder_with_root(root),
+        None => Account::builder(),
+    };
+    // One account file per CA
+    let ca: String = options
+        .ca
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = storage.join(format!("account_{}.json", ca));
+    if let Ok(json) = tokio::fs::read_to_string(&path).await {
+        let credentials: AccountCredentials = serde_json::from_str(&json)?;
+        return Ok(builder()?.from_credentials(credentials).await?);
+    }
+
+    let contact = options
+        .email
+        .as_ref()
+        .map(|email| format!("mailto:{}", email));
+    let contact: Vec<&str> = contact.iter().map(|c| c.as_str()).collect();
+    let new_account = NewAccount {
+        contact: &contact,
+        terms_of_service_agreed: true,
+        only_return_existing: false,
+    };
+    let (account, credentials) = builder()?
+        .create(&new_account, options.ca.clone(), None)
+        .await?;
+    write_private(&path, &serde_json::to_string_pretty(&credentials)?).await?;
+    info!("Registered an ACME account with {}", options.ca);
+    Ok(account)
+}
+
+/// Self-signed certificate proving control of `host` to a TLS-ALPN-01 validation (RFC 8737)
+fn alpn_certificate(host: &str, key_authorization_digest: &[u8]) -> AcmeResult<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![host.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+        key_authorization_digest,
+    )];
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+    // Not `from_der`: its consistency check doesn't parse the critical acmeIdentifier extension
+    let key = crypto_provider().key_provider.load_private_key(key)?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+fn certified_key(chain_pem: &str, key_pem: &str) -> AcmeResult<CertifiedKey> {
+    let chain =
+        CertificateDer::pem_slice_iter(chain_pem.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err("No certificate in the chain".into());
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes())?;
+    Ok(CertifiedKey::from_der(chain, key, &crypto_provider())?)
+}
+
+/// When a third of the certificate's lifetime is left
+fn renewal_time(cert: &CertificateDer) -> AcmeResult<SystemTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)?;
+    let not_before = cert.validity().not_before.timestamp();
+    let not_after = cert.validity().not_after.timestamp();
+    let renew_at = not_after - (not_after - not_before) / 3;
+    Ok(UNIX_EPOCH + Duration::from_secs(renew_at.max(0) as u64))
+}
+
+fn stored_paths(storage: &Path, host: &str) -> (PathBuf, PathBuf) {
+    (
+        storage.join(format!("{}.crt", host)),
+        storage.join(format!("{}.key", host)),
+    )
+}
+
+async fn load_stored(storage: &Path, host: &str) -> AcmeResult<Option<(CertifiedKey, SystemTime)>> {
+    let (cert_path, key_path) = stored_paths(storage, host);
+    let chain_pem = match tokio::fs::read_to_string(&cert_path).await {
+        Ok(pem) => pem,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let key_pem = tokio::fs::read_to_string(&key_path).await?;
+    let certified = certified_key(&chain_pem, &key_pem)?;
+    let renew_at = renewal_time(&certified.cert[0])?;
+    Ok(Some((certified, renew_at)))
+}
+
+/// Replaces `path` with `contents` at once, readable by the owner only as it may hold a key
+async fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acme::{
+        alpn_certificate, load_stored, next_retry_delay, renewal_time, write_private, AcmeState,
+        MAX_RETRY_DELAY, RETRY_DELAY,
+    };
+    use std::error::Error;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_stored_certificate() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-acme-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        assert!(load_stored(&dir, "example.com").await.unwrap().is_none());
+
+        let mut params = rcgen::CertificateParams::new(vec!["example.com".to_string()])?;
+        params.not_before = rcgen::date_time_ymd(2030, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, 4, 1);
+        let key = rcgen::KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        write_private(&dir.join("example.com.crt"), &cert.pem()).await?;
+        write_private(&dir.join("example.com.key"), &key.serialize_pem()).await?;
+
+        let (certified, renew_at) = load_stored(&dir, "example.com").await.unwrap().unwrap();
+        assert_eq!(certified.cert[0], *cert.der());
+        // 90 days of validity, renewed 30 days before the end
+        let not_after = UNIX_EPOCH + Duration::from_secs(1_901_232_000);
+        assert_eq!(renew_at, not_after - Duration::from_secs(30 * 24 * 60 * 60));
+        assert_eq!(renewal_time(cert.der()).unwrap(), renew_at);
+        assert!(renew_at > SystemTime::now());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("example.com.key"))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_challenges() -> Result<(), Box<dyn Error>> {
+        let state = AcmeState::default();
+        state
+            .http_challenges
+            .write()
+            .unwrap()
+            .insert("tok3n".to_string(), "tok3n.thumbprint".to_string());
+        assert_eq!(
+            state.http_challenge("/.well-known/acme-challenge/tok3n"),
+            Some("tok3n.thumbprint".to_string())
+        );
+        assert_eq!(
+            state.http_challenge("/.well-known/acme-challenge/other"),
+            None
+        );
+        assert_eq!(state.http_challenge("/tok3n"), None);
+
+        // The digest is carried in the critical acmeIdentifier extension
+        let digest = [7u8; 32];
+        let certified = alpn_certificate("example.com", &digest).unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&certified.cert[0])?;
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert!(extension.critical);
+        assert!(extension.value.ends_with(&digest));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_delay() -> Result<(), Box<dyn Error>> {
+        let mut delay = RETRY_DELAY;
+        let mut failures_in_first_hour = 0;
+        let mut elapsed = Duration::ZERO;
+        while elapsed < Duration::from_secs(3600) {
+            failures_in_first_hour += 1;
+            elapsed += delay;
+            delay = next_retry_delay(delay);
+        }
+        assert!(failures_in_first_hour < 5);
+        assert_eq!(
+            next_retry_delay(Duration::from_secs(40 * 60)),
+            Duration::from_secs(80 * 60)
+        );
+        for _ in 0..20 {
+            delay = next_retry_delay(delay);
+        }
+        assert_eq!(delay, MAX_RETRY_DELAY);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use crate::client_auth::ClientCert;
+use crate::hop_by_hop::strip_hop_by_hop;
+use crate::{directive_process, Server};
+use bytes::Bytes;
+use http::header::{CONNECTION, CONTENT_LENGTH, COOKIE, EXPECT, HOST, TRANSFER_ENCODING};
+use http::request::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Response, StatusCode};
+use httparse::Status;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+/// Buffer between a stream and the pipeline serving it
+const PIPE_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_HEADERS: usize = 64;
+
+/// Request body of a stream, as its protocol delivers it
+pub trait RequestBody {
+    async fn next(&mut self) -> io::Result<Option<Bytes>>;
+}
+
+/// Lets a stream of another HTTP version be served by `directive_process`, which reads it as
+/// an HTTP/1.1 request from one end of an in-memory pipe and answers on it. Returns the
+/// stream's end of the pipe and the future serving the other; once it is done the pipe is
+/// closed, which marks the end of the response.
+pub fn pipeline(
+    peer: SocketAddr,
+    client: Option<ClientCert>,
+    server: &Server,
+) -> (
+    (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>),
+    impl Future<Output = ()> + '_,
+) {
+    let (pipe, mut pipeline) = tokio::io::duplex(PIPE_SIZE);
+    let serving = async move {
+        directive_process(&mut pipeline, peer, client, server).await;
+    };
+    (tokio::io::split(pipe), serving)
+}
+
+/// Writes a request to the pipeline in HTTP/1.1 form, body included. Fails once the
+/// pipeline is done without reading all of the body, which is fine.
+pub async fn write_request<B, W>(parts: &Parts, mut body: B, pipe: &mut W) -> io::Result<()>
+where
+    B: RequestBody,
+    W: AsyncWrite + Unpin,
+{
+    let declared = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = declared {
+        pipe.write_all(&request_head(parts, Some(length))).await?;
+        while let Some(data) = body.next().await? {
+            pipe.write_all(&data).await?;
+        }
+        return pipe.flush().await;
+    }
+
+    // Bodies sent without a length are passed on chunked, as they arrive
+    let Some(first) = body.next().await? else {
+        pipe.write_all(&request_head(parts, Some(0))).await?;
+        return pipe.flush().await;
+    };
+    pipe.write_all(&request_head(parts, None)).await?;
+    let mut data = Some(first);
+    while let Some(chunk) = data {
+        // An empty chunk would end the body
+        if !chunk.is_empty() {
+            pipe.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                .await?;
+            pipe.write_all(&chunk).await?;
+            pipe.write_all(b"\r\n").await?;
+        }
+        data = body.next().await?;
+    }
+    pipe.write_all(b"0\r\n\r\n").await?;
+    pipe.flush().await
+}
+
+/// The HTTP/1.1 head of a request with a body of `length` bytes, chunked without one.
+/// The pipeline closes the connection after answering it.
+fn request_head(parts: &Parts, length: Option<u64>) -> Vec<u8> {
+    let mut headers = parts.headers.clone();
+    strip_hop_by_hop(&mut headers);
+    headers.remove(EXPECT);
+    if !headers.contains_key(HOST) {
+        if let Some(host) = parts
+            .uri
+            .authority()
+            .and_then(|a| HeaderValue::from_str(a.as_str()).ok())
+        {
+            headers.insert(HOST, host);
+        }
+    }
+    // Clients may send each cookie in its own field, HTTP/1.1 wants them in one
+    let cookies: Vec<&[u8]> = headers
+        .get_all(COOKIE)
+        .iter()
+        .map(|v| v.as_bytes())
+        .collect();
+    if cookies.len() > 1 {
+        if let Ok(cookie) = HeaderValue::from_bytes(&cookies.join(&b"; "[..])) {
+            headers.insert(COOKIE, cookie);
+        }
+    }
+    match length {
+        Some(length) if length > 0 || headers.contains_key(CONTENT_LENGTH) => {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+        Some(_) => {}
+        None => {
+            headers.remove(CONTENT_LENGTH);
+            headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        }
+    }
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+
+    let target = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let mut head = format!("{} {} HTTP/1.1\r\n", parts.method, target).into_bytes();
+    for (key, value) in headers.iter() {
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Reads the head of the pipeline's response, skipping interim ones. It comes without
+/// connection-specific headers, the body follows without its HTTP/1.1 framing.
+pub async fn read_response<R>(pipe: R) -> io::Result<(Response<()>, ResponseBody<R>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(pipe);
+    loop {
+        let mut head = Vec::new();
+        loop {
+            let start = head.len();
+            if reader.read_until(b'\n', &mut head).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = &head[start..];
+            if line == b"\r\n" || line == b"\n" {
+                break;
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+        let mut parsed = httparse::Response::new(&mut headers);
+        if !matches!(parsed.parse(&head), Ok(Status::Complete(_))) {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let status = StatusCode::from_u16(parsed.code.unwrap_or(0)).map_err(io::Error::other)?;
+        if status.is_informational() {
+            continue;
+        }
+
+        let mut header_map = HeaderMap::with_capacity(parsed.headers.len());
+        for header in parsed.headers.iter() {
+            let name = HeaderName::from_bytes(header.name.as_bytes());
+            let value = HeaderValue::from_bytes(header.value);
+            if let (Ok(name), Ok(value)) = (name, value) {
+                header_map.append(name, value);
+            }
+        }
+        let chunked = header_map
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .any(|v| v.to_str().is_ok_and(|v| v.contains("chunked")));
+        strip_hop_by_hop(&mut header_map);
+
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        *response.headers_mut() = header_map;
+        let body = ResponseBody {
+            reader,
+            chunked,
+            remaining: 0,
+            done: false,
+        };
+        return Ok((response, body));
+    }
+}
+
+/// Body of the pipeline's response
+pub struct ResponseBody<R> {
+    reader: BufReader<R>,
+    chunked: bool,
+    remaining: u64, // Left of the current chunk
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> ResponseBody<R> {
+    /// Reads the next piece of the body, `None` once all of it has been read.
+    pub async fn next(&mut self) -> io::Result<Option<Bytes>> {
+        if self.done {
+            return Ok(None);
+        }
+        if !self.chunked {
+            // The pipeline closes its end once the body is written
+            let mut buf = vec![0; 16384];
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                self.done = true;
+                return Ok(None);
+            }
+            buf.truncate(n);
+            return Ok(Some(Bytes::from(buf)));
+        }
+
+        let mut line = Vec::new();
+        if self.remaining == 0 {
+            if self.reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.remaining = match httparse::parse_chunk_size(&line) {
+                Ok(Status::Complete((_, size))) => size,
+                _ => return Err(io::ErrorKind::InvalidData.into()),
+            };
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(None);
+            }
+        }
+        let mut buf = vec![0; self.remaining.min(16384) as usize];
+        self.reader.read_exact(&mut buf).await?;
+        self.remaining -= buf.len() as u64;
+        if self.remaining == 0 {
+            // CRLF closing the chunk
+            line.clear();
+            self.reader.read_until(b'\n', &mut line).await?;
+        }
+        Ok(Some(Bytes::from(buf)))
+    }
+}
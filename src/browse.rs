@@ -0,0 +1,382 @@
+use crate::encode::Encode;
+use crate::response::{error_response, send_response};
+use http::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use http::{Request, Response, StatusCode};
+use log::debug;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::json;
+use std::cmp::Ordering;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+
+/// Characters escaped in the links of a listing, everything that isn't safe in a path segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn url(&self) -> String {
+        let mut url = utf8_percent_encode(&self.name, SEGMENT).to_string();
+        if self.is_dir {
+            url.push('/');
+        }
+        url
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortBy {
+    Name,
+    Size,
+    Time,
+}
+
+/// Responds with the listing of `dir`: HTML, or JSON for clients asking for `application/json`.
+/// `?sort=name|size|time&order=asc|desc` picks the order, directories always come first.
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+pub async fn directive<S>(
+    dir: &Path,
+    show_hidden: bool,
+    encode: Option<&Encode>,
+    request: &Request<Vec<u8>>,
+    socket: &mut S,
+    req_opt: Option<&Request<Vec<u8>>>,
+) where
+    S: AsyncWriteExt + Unpin,
+{
+    // Relative links in the listing only resolve against a path ending with a slash
+    let path = request.uri().path();
+    if !path.ends_with('/') {
+        let location = slash_location(path, request.uri().query());
+        let response = Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header(LOCATION, location)
+            .body(Vec::new())
+            .unwrap();
+        let _ = send_response(socket, response, req_opt).await;
+        return;
+    }
+
+    let mut entries = match read_entries(dir, show_hidden).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Error: {}", err);
+            let response = error_response(StatusCode::NOT_FOUND);
+            let _ = send_response(socket, response, req_opt).await;
+            return;
+        }
+    };
+
+    let (sort_by, descending) = sort_params(request.uri().query().unwrap_or(""));
+    sort_entries(&mut entries, sort_by, descending);
+
+    let wants_json = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    let (content_type, body) = if wants_json {
+        ("application/json", render_json(&entries))
+    } else {
+        ("text/html; charset=utf-8", render_html(path, &entries))
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(body.into_bytes())
+        .unwrap();
+    if let Some(encode) = encode {
+        encode.encode_response(request, &mut response).await;
+    }
+    let _ = send_response(socket, response, req_opt).await;
+}
+
+async fn read_entries(dir: &Path, show_hidden: bool) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        let name = dir_entry.file_name().to_string_lossy().to_string();
+        if !show_hidden && name.starts_with('.') {
+            continue;
+        }
+        // Follows symlinks, so a link to a directory is listed as one
+        let Ok(metadata) = tokio::fs::metadata(dir_entry.path()).await else {
+            continue; // Dangling symlink
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Where a directory requested without its trailing slash is found. Leading slashes are
+/// collapsed, `//name/` would be taken for another host.
+fn slash_location(path: &str, query: Option<&str>) -> String {
+    let path = path.trim_start_matches('/');
+    match query {
+        Some(query) => format!("/{}/?{}", path, query),
+        None => format!("/{}/", path),
+    }
+}
+
+fn sort_params(query: &str) -> (SortBy, bool) {
+    let mut sort_by = SortBy::Name;
+    let mut descending = false;
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some(("sort", "size")) => sort_by = SortBy::Size,
+            Some(("sort", "time")) => sort_by = SortBy::Time,
+            Some(("sort", "name")) => sort_by = SortBy::Name,
+            Some(("order", "desc")) => descending = true,
+            Some(("order", "asc")) => descending = false,
+            _ => {}
+        }
+    }
+    (sort_by, descending)
+}
+
+fn sort_entries(entries: &mut [Entry], sort_by: SortBy, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort_by {
+            SortBy::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Time => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        // Directories first, whatever the order
+        match (a.is_dir, b.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+}
+
+fn render_json(entries: &[Entry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "url": entry.url(),
+                "is_dir": entry.is_dir,
+                "size": entry.size,
+                "mod_time": entry
+                    .modified
+                    .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            })
+        })
+        .collect();
+    serde_json::Value::Array(entries).to_string()
+}
+
+fn render_html(path: &str, entries: &[Entry]) -> String {
+    let title = html_escape(&percent_encoding::percent_decode_str(path).decode_utf8_lossy());
+    let mut html = String::with_capacity(512 + entries.len() * 160);
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>Index of {}</title>\n", title));
+    html.push_str(
+        "<style>body{font-family:sans-serif}td,th{padding:2px 12px;text-align:left}\
+         td.size{text-align:right}</style>\n</head>\n<body>\n",
+    );
+    html.push_str(&format!("<h1>Index of {}</h1>\n<table>\n", title));
+    html.push_str(
+        "<tr><th><a href=\"?sort=name\">Name</a></th><th><a href=\"?sort=size&amp;order=desc\">\
+         Size</a></th><th><a href=\"?sort=time&amp;order=desc\">Modified</a></th></tr>\n",
+    );
+    if path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let name = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            human_size(entry.size)
+        };
+        let modified = entry
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td class=\"size\">{}</td><td>{}</td></tr>\n",
+            html_escape(&entry.url()),
+            html_escape(&name),
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::browse::{
+        html_escape, human_size, render_html, render_json, slash_location, sort_entries,
+        sort_params, Entry, SortBy,
+    };
+    use std::error::Error;
+    use std::time::{Duration, SystemTime};
+
+    fn entries() -> Vec<Entry> {
+        let at = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        vec![
+            Entry {
+                name: "b.txt".to_string(),
+                is_dir: false,
+                size: 10,
+                modified: at(300),
+            },
+            Entry {
+                name: "A.txt".to_string(),
+                is_dir: false,
+                size: 2000,
+                modified: at(100),
+            },
+            Entry {
+                name: "docs".to_string(),
+                is_dir: true,
+                size: 0,
+                modified: at(200),
+            },
+        ]
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort() -> Result<(), Box<dyn Error>> {
+        let mut list = entries();
+        let (sort_by, descending) = sort_params("");
+        sort_entries(&mut list, sort_by, descending);
+        assert_eq!(names(&list), vec!["docs", "A.txt", "b.txt"]);
+
+        let (sort_by, descending) = sort_params("sort=size&order=desc");
+        assert_eq!((sort_by, descending), (SortBy::Size, true));
+        sort_entries(&mut list, sort_by, descending);
+        assert_eq!(names(&list), vec!["docs", "A.txt", "b.txt"]);
+
+        sort_entries(&mut list, SortBy::Time, true);
+        assert_eq!(names(&list), vec!["docs", "b.txt", "A.txt"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render() -> Result<(), Box<dyn Error>> {
+        let mut list = entries();
+        list.push(Entry {
+            name: "<script> & \"x\".html".to_string(),
+            is_dir: false,
+            size: 1,
+            modified: None,
+        });
+
+        let html = render_html("/files/", &list);
+        assert!(html.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("&lt;script&gt; &amp; &quot;x&quot;.html"));
+        assert!(html.contains("href=\"%3Cscript%3E%20&amp;%20%22x%22.html\""));
+        assert!(!html.contains("<script>"));
+        assert!(!render_html("/", &list).contains("<a href=\"../\">"));
+
+        let json: serde_json::Value = serde_json::from_str(&render_json(&list))?;
+        assert_eq!(json[0]["name"], "b.txt");
+        assert_eq!(json[0]["size"], 10);
+        assert_eq!(json[0]["mod_time"], 300);
+        assert_eq!(json[2]["url"], "docs/");
+        assert_eq!(json[2]["is_dir"], true);
+
+        assert_eq!(html_escape("a'b"), "a&#39;b");
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slash_location() -> Result<(), Box<dyn Error>> {
+        assert_eq!(slash_location("/files", None), "/files/");
+        assert_eq!(
+            slash_location("/files", Some("sort=size")),
+            "/files/?sort=size"
+        );
+        assert_eq!(slash_location("//evil.example", None), "/evil.example/");
+        assert_eq!(slash_location("///a/b", None), "/a/b/");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ServerConnection, WebPkiClientVerifier};
+use rustls::RootCertStore;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+
+/// Whether clients must present a certificate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuthMode {
+    Require,
+    /// Asked for, but clients without one are let in too
+    Request,
+}
+
+impl ClientAuthMode {
+    pub fn from_arg(arg: &str) -> Option<ClientAuthMode> {
+        match arg {
+            "require" => Some(ClientAuthMode::Require),
+            "request" => Some(ClientAuthMode::Request),
+            _ => None,
+        }
+    }
+}
+
+/// `client_auth` option of a `tls` directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAuth {
+    pub mode: ClientAuthMode,
+    /// PEM bundle of the CAs client certificates are verified against
+    pub ca: String,
+}
+
+impl ClientAuth {
+    pub fn verifier(
+        &self,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&self.ca)? {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(format!("No CA certificate found in {}", self.ca).into());
+        }
+        let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+        let verifier = match self.mode {
+            ClientAuthMode::Require => builder.build()?,
+            ClientAuthMode::Request => builder.allow_unauthenticated().build()?,
+        };
+        Ok(verifier)
+    }
+}
+
+/// Identity of a client that presented a verified certificate, found in the request extensions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCert {
+    /// Distinguished name, e.g. `CN=alice, O=Example`
+    pub subject: String,
+    /// Subject alternative names, e.g. `DNS:alice.example.com` or `email:alice@example.com`
+    pub sans: Vec<String>,
+    /// CA bundle it was verified against
+    pub ca: String,
+}
+
+/// The identity of the client on `connection`, if it authenticated with `auth`
+pub fn client_cert(connection: &ServerConnection, auth: &ClientAuth) -> Option<ClientCert> {
+    let leaf = connection.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf).ok()?;
+    let mut sans = Vec::new();
+    if let Ok(Some(extension)) = cert.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(dns) => sans.push(format!("DNS:{}", dns)),
+                GeneralName::RFC822Name(email) => sans.push(format!("email:{}", email)),
+                GeneralName::URI(uri) => sans.push(format!("URI:{}", uri)),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                        _ => None,
+                    };
+                    if let Some(ip) = ip {
+                        sans.push(format!("IP:{}", ip));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Some(ClientCert {
+        subject: cert.subject().to_string(),
+        sans,
+        ca: auth.ca.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_auth::{client_cert, ClientAuth, ClientAuthMode, ClientCert};
+    use crate::tls::crypto_provider;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair, SanType};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+    use std::error::Error;
+    use std::sync::Arc;
+
+    /// Runs a handshake between the two in memory
+    fn handshake(
+        client: &mut ClientConnection,
+        server: &mut ServerConnection,
+    ) -> Result<(), Box<dyn Error>> {
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf)?;
+            server.read_tls(&mut buf.as_slice())?;
+            server.process_new_packets()?;
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf)?;
+            client.read_tls(&mut buf.as_slice())?;
+            client.process_new_packets()?;
+        }
+        Err("Handshake did not finish".into())
+    }
+
+    #[test]
+    fn test_client_auth() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-client-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let provider = crypto_provider();
+
+        let mut ca_params = CertificateParams::new(Vec::new())?;
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca_key = KeyPair::generate()?;
+        let ca_cert = ca_params.self_signed(&ca_key)?;
+        let ca = Issuer::new(ca_params, ca_key);
+        let ca_path = dir.join("ca.pem").to_string_lossy().into_owned();
+        std::fs::write(&ca_path, ca_cert.pem())?;
+
+        let mut alice_params = CertificateParams::new(vec!["alice.example.com".to_string()])?;
+        alice_params
+            .distinguished_name
+            .push(DnType::CommonName, "alice");
+        alice_params
+            .subject_alt_names
+            .push(SanType::Rfc822Name("alice@example.com".try_into()?));
+        let alice_key = KeyPair::generate()?;
+        let alice = alice_params.signed_by(&alice_key, &ca)?;
+
+        let server_cert = rcgen::generate_simple_self_signed(vec!["admin.test".to_string()])?;
+        let mut roots = RootCertStore::empty();
+        roots.add(server_cert.cert.der().clone())?;
+
+        let connect =
+            |mode: ClientAuthMode, with_cert: bool| -> Result<Option<ClientCert>, Box<dyn Error>> {
+                let auth = ClientAuth {
+                    mode,
+                    ca: ca_path.clone(),
+                };
+                let server_config = ServerConfig::builder_with_provider(provider.clone())
+                    .with_safe_default_protocol_versions()?
+                    .with_client_cert_verifier(auth.verifier(provider.clone())?)
+                    .with_single_cert(
+                        vec![server_cert.cert.der().clone()],
+                        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+                            server_cert.signing_key.serialize_der(),
+                        )),
+                    )?;
+                let builder = ClientConfig::builder_with_provider(provider.clone())
+                    .with_safe_default_protocol_versions()?
+                    .with_root_certificates(roots.clone());
+                let client_config = if with_cert {
+                    builder.with_client_auth_cert(
+                        vec![alice.der().clone()],
+                        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(alice_key.serialize_der())),
+                    )?
+                } else {
+                    builder.with_no_client_auth()
+                };
+                let mut server = ServerConnection::new(Arc::new(server_config))?;
+                let mut client = ClientConnection::new(
+                    Arc::new(client_config),
+                    ServerName::try_from("admin.test")?,
+                )?;
+                handshake(&mut client, &mut server)?;
+                Ok(client_cert(&server, &auth))
+            };
+
+        let expected = ClientCert {
+            subject: "CN=alice".to_string(),
+            sans: vec![
+                "DNS:alice.example.com".to_string(),
+                "email:alice@example.com".to_string(),
+            ],
+            ca: ca_path.clone(),
+        };
+        assert_eq!(
+            connect(ClientAuthMode::Require, true)?,
+            Some(expected.clone())
+        );
+        assert!(connect(ClientAuthMode::Require, false).is_err());
+        assert_eq!(connect(ClientAuthMode::Request, true)?, Some(expected));
+        assert_eq!(connect(ClientAuthMode::Request, false)?, None);
+
+        // Bundles without a certificate are refused up front
+        let empty = dir.join("empty.pem").to_string_lossy().into_owned();
+        std::fs::write(&empty, "")?;
+        let auth = ClientAuth {
+            mode: ClientAuthMode::Require,
+            ca: empty,
+        };
+        assert!(auth.verifier(provider.clone()).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use http::header::{IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use http::{Method, Request};
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Validators of a served file, derived from the metadata fetched when it was opened.
+#[derive(Debug)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let mtime = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", mtime, metadata.len()),
+            last_modified: modified.map(httpdate::fmt_http_date),
+            modified,
+        }
+    }
+
+    /// Whether the client's cached copy is current and a 304 can be sent instead,
+    /// evaluating `If-None-Match` before `If-Modified-Since` as RFC 9110 section 13.2.2 requires.
+    pub fn not_modified<B>(&self, request: &Request<B>) -> bool {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return false;
+        }
+        if let Some(if_none_match) = request.headers().get(IF_NONE_MATCH) {
+            let if_none_match = if_none_match.to_str().unwrap_or("");
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    .any(|tag| weak_eq(tag.trim(), &self.etag));
+        }
+        if let Some(since) = request.headers().get(IF_MODIFIED_SINCE) {
+            let since = since.to_str().ok().map(httpdate::parse_http_date);
+            if let (Some(Ok(since)), Some(modified)) = (since, self.modified) {
+                // HTTP dates have one second resolution
+                return modified
+                    .duration_since(since)
+                    .map(|newer| newer.as_secs() == 0)
+                    .unwrap_or(true);
+            }
+        }
+        false
+    }
+
+    /// Whether an `If-Range` precondition still holds, i.e. the client's partial copy is of the
+    /// current version of the file and the `Range` header may be honored.
+    pub fn if_range_matches(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with("W/") {
+            // If-Range requires a strong comparison, weak tags never match
+            return false;
+        }
+        if if_range.starts_with('"') {
+            return if_range == self.etag;
+        }
+        match (httpdate::parse_http_date(if_range), self.modified) {
+            (Ok(date), Some(modified)) => {
+                httpdate::fmt_http_date(date) == httpdate::fmt_http_date(modified)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Weak entity tag comparison: `W/"x"` and `"x"` are equivalent
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conditional::Validators;
+    use http::Request;
+    use std::error::Error;
+    use std::time::{Duration, SystemTime};
+
+    fn validators() -> Validators {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        Validators {
+            etag: "\"abc-10\"".to_string(),
+            last_modified: Some(httpdate::fmt_http_date(modified)),
+            modified: Some(modified),
+        }
+    }
+
+    fn request(header: &str, value: &str) -> Request<Vec<u8>> {
+        Request::builder()
+            .uri("/")
+            .header(header, value)
+            .body(Vec::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_not_modified() -> Result<(), Box<dyn Error>> {
+        let v = validators();
+        assert_eq!(
+            v.last_modified.as_deref(),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+
+        assert!(v.not_modified(&request("If-None-Match", "\"abc-10\"")));
+        assert!(v.not_modified(&request("If-None-Match", "\"x\", W/\"abc-10\"")));
+        assert!(v.not_modified(&request("If-None-Match", "*")));
+        assert!(!v.not_modified(&request("If-None-Match", "\"abc-11\"")));
+
+        let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert!(v.not_modified(&request("If-Modified-Since", since)));
+        let later = "Mon, 07 Nov 1994 08:49:37 GMT";
+        assert!(v.not_modified(&request("If-Modified-Since", later)));
+        let earlier = "Sun, 06 Nov 1994 08:49:36 GMT";
+        assert!(!v.not_modified(&request("If-Modified-Since", earlier)));
+        assert!(!v.not_modified(&request("If-Modified-Since", "garbage")));
+
+        // If-None-Match wins over If-Modified-Since
+        let req = Request::builder()
+            .uri("/")
+            .header("If-None-Match", "\"other\"")
+            .header("If-Modified-Since", since)
+            .body(Vec::<u8>::new())
+            .unwrap();
+        assert!(!v.not_modified(&req));
+
+        let post = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("If-None-Match", "*")
+            .body(Vec::<u8>::new())
+            .unwrap();
+        assert!(!v.not_modified(&post));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_if_range() -> Result<(), Box<dyn Error>> {
+        let v = validators();
+        assert!(v.if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!v.if_range_matches("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(v.if_range_matches("\"abc-10\""));
+        assert!(!v.if_range_matches("W/\"abc-10\""));
+        assert!(!v.if_range_matches("\"abc-11\""));
+        assert!(!v.if_range_matches("garbage"));
+
+        Ok(())
+    }
+}
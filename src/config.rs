@@ -1,7 +1,22 @@
+use crate::acme::{AcmeChallenge, AcmeOptions};
+use crate::client_auth::{ClientAuth, ClientAuthMode};
+use crate::encode::{Encode, Encoding, DEFAULT_MINIMUM_LENGTH};
+use crate::file_server::FileServerOptions;
+use crate::forwarded::{parse_trusted_proxy, HostHeader};
+use crate::mime_types::MimeTypes;
+use crate::reverse_proxy::ProxyOptions;
+use crate::tls::{
+    cipher_suite_name, host_name, kx_group_name, TlsOptions, TlsVersion, ALPN_PROTOCOLS,
+};
+use crate::upstream::{LbPolicy, Upstreams};
+use http::HeaderValue;
 use kdl::KdlDocument;
 use log::{debug, error};
+use regex::Regex;
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum Directive {
@@ -9,10 +24,12 @@ pub enum Directive {
         pattern: String,
         path: String,
     },
-    FileServer,
+    FileServer {
+        options: FileServerOptions,
+    },
     ReverseProxy {
         pattern: String,
-        destination: String,
+        upstreams: Arc<Upstreams>,
     },
     Redir {
         destination: String,
@@ -20,6 +37,35 @@ pub enum Directive {
     Tls {
         cert: String,
         key: String,
+        client_auth: Option<ClientAuth>,
+    },
+    TlsAuto {
+        acme: AcmeOptions,
+        client_auth: Option<ClientAuth>,
+    },
+    TlsOptions {
+        options: TlsOptions,
+    },
+    H2c,
+    Http3,
+    /// A TLS host left without the redirect from plain HTTP
+    NoHttpRedirect,
+    /// Added to the plain HTTP listener for TLS hosts: sends clients to the host's HTTPS port
+    HttpsRedirect {
+        port: u16,
+    },
+    MaxConnections {
+        limit: usize,
+    },
+    KeepAlive {
+        timeout: Option<Duration>,
+        requests: Option<usize>,
+    },
+    MimeTypes {
+        types: MimeTypes,
+    },
+    Encode {
+        encode: Encode,
     },
 }
 
@@ -38,7 +84,7 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
                     "root" => {
                         let args = get_string_args(child_node);
                         if args.len() >= 2 {
-                            let pattern = args.get(0).unwrap().to_string();
+                            let pattern = args.first().unwrap().to_string();
                             let path = args.get(1).unwrap().to_string();
                             directives.push(Directive::Root { pattern, path });
                         } else {
@@ -48,16 +94,45 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
                         }
                     }
                     "file_server" => {
-                        directives.push(Directive::FileServer);
+                        let mut options = FileServerOptions::default();
+                        for option in child_node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+                            // A bare flag like `browse` turns the option on
+                            let value = match option.entries().first() {
+                                Some(entry) => entry.value().as_bool(),
+                                None => Some(true),
+                            };
+                            match (option.name().value(), value) {
+                                ("follow_symlinks", Some(value)) => {
+                                    options.follow_symlinks = value;
+                                }
+                                ("browse", Some(value)) => {
+                                    options.browse = value;
+                                }
+                                ("show_hidden", Some(value)) => {
+                                    options.show_hidden = value;
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "Invalid 'file_server' directive for host {}",
+                                        hostname
+                                    )
+                                    .into());
+                                }
+                            }
+                        }
+                        directives.push(Directive::FileServer { options });
                     }
                     "reverse_proxy" => {
                         let args = get_string_args(child_node);
                         if args.len() >= 2 {
-                            let pattern = args.get(0).unwrap().to_string();
-                            let destination = args.get(1).unwrap().to_string();
+                            let pattern = args.first().unwrap().to_string();
+                            let options = proxy_options(child_node).ok_or_else(|| {
+                                format!("Invalid 'reverse_proxy' directive for host {}", hostname)
+                            })?;
+                            let upstreams = Upstreams::new(&args[1..], &options)?;
                             directives.push(Directive::ReverseProxy {
                                 pattern,
-                                destination,
+                                upstreams: Arc::new(upstreams),
                             });
                         } else {
                             return Err(format!(
@@ -69,8 +144,8 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
                     }
                     "redir" => {
                         let args = get_string_args(child_node);
-                        if args.len() >= 1 {
-                            let destination = args.get(0).unwrap().to_string();
+                        if !args.is_empty() {
+                            let destination = args.first().unwrap().to_string();
                             directives.push(Directive::Redir { destination });
                         } else {
                             return Err(
@@ -80,12 +155,27 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
                     }
                     "tls" => {
                         let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let cert_path = args.get(0).unwrap().to_string();
+                        let invalid = || format!("Invalid 'tls' directive for host {}", hostname);
+                        // Clients are asked for certificates by SNI, which never names the catch-all host
+                        let client_auth = client_auth(child_node)
+                            .filter(|auth| auth.is_none() || host_name(&hostname) != "*")
+                            .ok_or_else(invalid)?;
+                        let options = child_node.children().map(|c| c.nodes()).unwrap_or(&[]);
+                        if args.first() == Some(&"auto") {
+                            // ACME can't prove control of every name a wildcard covers over HTTP
+                            let acme = acme_options(child_node)
+                                .filter(|_| !hostname.starts_with('*'))
+                                .ok_or_else(invalid)?;
+                            directives.push(Directive::TlsAuto { acme, client_auth });
+                        } else if args.len() >= 2
+                            && options.iter().all(|o| o.name().value() == "client_auth")
+                        {
+                            let cert_path = args.first().unwrap().to_string();
                             let key_path = args.get(1).unwrap().to_string();
                             directives.push(Directive::Tls {
                                 cert: cert_path,
                                 key: key_path,
+                                client_auth,
                             });
                         } else {
                             return Err(
@@ -93,6 +183,139 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
                             );
                         }
                     }
+                    "h2c" | "http3" => {
+                        let name = child_node.name().value();
+                        let enabled = get_flag(child_node).ok_or_else(|| {
+                            format!("Invalid '{}' directive for host {}", name, hostname)
+                        })?;
+                        if enabled {
+                            directives.push(if name == "h2c" {
+                                Directive::H2c
+                            } else {
+                                Directive::Http3
+                            });
+                        }
+                    }
+                    "tls_options" => {
+                        let options = tls_options(child_node).ok_or_else(|| {
+                            format!("Invalid 'tls_options' directive for host {}", hostname)
+                        })?;
+                        directives.push(Directive::TlsOptions { options });
+                    }
+                    "http_redirect" => {
+                        let enabled = get_flag(child_node).ok_or_else(|| {
+                            format!("Invalid 'http_redirect' directive for host {}", hostname)
+                        })?;
+                        if !enabled {
+                            directives.push(Directive::NoHttpRedirect);
+                        }
+                    }
+                    "max_connections" => {
+                        let args = get_int_args(child_node);
+                        match args.first() {
+                            Some(&limit) if limit > 0 => {
+                                directives.push(Directive::MaxConnections {
+                                    limit: limit as usize,
+                                });
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "Invalid 'max_connections' directive for host {}",
+                                    hostname
+                                )
+                                .into());
+                            }
+                        }
+                    }
+                    "keep_alive" => {
+                        let mut timeout = None;
+                        let mut requests = None;
+                        for option in child_node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+                            let value = get_int_args(option).first().copied().unwrap_or(0);
+                            match option.name().value() {
+                                "timeout" if value > 0 => {
+                                    timeout = Some(Duration::from_secs(value as u64));
+                                }
+                                "requests" if value > 0 => {
+                                    requests = Some(value as usize);
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "Invalid 'keep_alive' directive for host {}",
+                                        hostname
+                                    )
+                                    .into());
+                                }
+                            }
+                        }
+                        directives.push(Directive::KeepAlive { timeout, requests });
+                    }
+                    "mime_types" => {
+                        let mut overrides = HashMap::new();
+                        let mut default = None;
+                        for mapping in child_node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+                            let extension = mapping.name().value();
+                            // Sent as the Content-Type of served files
+                            let mime = match get_string_args(mapping).first() {
+                                Some(mime) if HeaderValue::from_str(mime).is_ok() => {
+                                    mime.to_string()
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "Invalid 'mime_types' directive for host {}",
+                                        hostname
+                                    )
+                                    .into());
+                                }
+                            };
+                            if extension == "default" {
+                                default = Some(mime);
+                            } else {
+                                overrides.insert(extension.to_string(), mime);
+                            }
+                        }
+                        directives.push(Directive::MimeTypes {
+                            types: MimeTypes::new(overrides, default),
+                        });
+                    }
+                    "encode" => {
+                        let args = get_string_args(child_node);
+                        let encodings = if args.is_empty() {
+                            vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]
+                        } else {
+                            match args.iter().map(|a| Encoding::from_name(a)).collect() {
+                                Some(encodings) => encodings,
+                                None => {
+                                    return Err(format!(
+                                        "Invalid 'encode' directive for host {}",
+                                        hostname
+                                    )
+                                    .into());
+                                }
+                            }
+                        };
+                        let mut minimum_length = DEFAULT_MINIMUM_LENGTH;
+                        for option in child_node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+                            match (option.name().value(), get_int_args(option).first()) {
+                                ("minimum_length", Some(&value)) if value >= 0 => {
+                                    minimum_length = value as u64;
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "Invalid 'encode' directive for host {}",
+                                        hostname
+                                    )
+                                    .into());
+                                }
+                            }
+                        }
+                        directives.push(Directive::Encode {
+                            encode: Encode {
+                                encodings,
+                                minimum_length,
+                            },
+                        });
+                    }
                     _ => {
                         return Err(format!(
                             "Unknown directive '{}' for host {}",
@@ -120,6 +343,143 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
     Ok(hosts)
 }
 
+/// Options block of a `reverse_proxy` directive, timeouts in seconds
+fn proxy_options(node: &kdl::KdlNode) -> Option<ProxyOptions> {
+    let mut options = ProxyOptions::default();
+    for option in node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+        let int = get_int_args(option).first().copied();
+        let seconds = int
+            .filter(|&v| v > 0)
+            .map(|v| Duration::from_secs(v as u64));
+        match option.name().value() {
+            "lb_policy" => options.lb_policy = LbPolicy::from_args(&get_string_args(option))?,
+            "lb_retries" => options.lb_retries = Some(int.filter(|&v| v >= 0)? as usize),
+            "health_uri" => {
+                options.health.uri = Some(get_string_args(option).first()?.to_string());
+            }
+            "health_interval" => options.health.interval = seconds?,
+            "health_timeout" => options.health.timeout = seconds?,
+            "health_status" => options.health.status = Some(int.filter(|&v| v > 0)? as u16),
+            "health_passes" => options.health.passes = int.filter(|&v| v > 0)? as usize,
+            "health_fails" => options.health.fails = int.filter(|&v| v > 0)? as usize,
+            "max_fails" => options.health.max_fails = int.filter(|&v| v > 0)? as usize,
+            "fail_duration" => options.health.fail_duration = Some(seconds?),
+            "trusted_proxies" => {
+                let args = get_string_args(option);
+                if args.is_empty() {
+                    return None;
+                }
+                for arg in args {
+                    options
+                        .forwarded
+                        .trusted_proxies
+                        .push(parse_trusted_proxy(arg)?);
+                }
+            }
+            "host_header" => {
+                options.forwarded.host = HostHeader::from_arg(get_string_args(option).first()?);
+            }
+            "strip_prefix" => {
+                options.rewrite.strip_prefix = Some(get_string_args(option).first()?.to_string());
+            }
+            "rewrite" => match get_string_args(option)[..] {
+                [regex, replacement] => {
+                    let regex = Regex::new(regex).ok()?;
+                    options.rewrite.rewrite = Some((regex, replacement.to_string()));
+                }
+                _ => return None,
+            },
+            "tunnel_idle_timeout" => options.tunnel_idle_timeout = seconds?,
+            "pool_max_idle" => options.pool_max_idle = int.filter(|&v| v >= 0)? as usize,
+            "pool_idle_timeout" => options.pool_idle_timeout = seconds?,
+            "connect_timeout" => options.connect_timeout = seconds?,
+            "read_timeout" => options.read_timeout = Some(seconds?),
+            "tls_ca" => options.tls_ca = Some(get_string_args(option).first()?.to_string()),
+            "tls_insecure_skip_verify" => options.tls_insecure_skip_verify = get_flag(option)?,
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+/// Options block of a `tls "auto"` directive
+fn acme_options(node: &kdl::KdlNode) -> Option<AcmeOptions> {
+    let mut options = AcmeOptions::default();
+    for option in node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+        let string = get_string_args(option).first().map(|s| s.to_string());
+        match option.name().value() {
+            "email" => options.email = Some(string?),
+            "ca" => options.ca = string?,
+            "ca_root" => options.ca_root = Some(string?),
+            "storage" => options.storage = string?,
+            "challenge" => options.challenge = AcmeChallenge::from_arg(&string?)?,
+            "http_port" => {
+                let port = get_int_args(option).first().copied()?;
+                options.http_port = u16::try_from(port).ok().filter(|&p| p > 0)?;
+            }
+            "client_auth" => {}
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+/// `client_auth "require"` or `"request"` with the CA bundle, in the block of a `tls` directive
+fn client_auth(node: &kdl::KdlNode) -> Option<Option<ClientAuth>> {
+    let mut auth = None;
+    for option in node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+        if option.name().value() != "client_auth" {
+            continue;
+        }
+        match get_string_args(option)[..] {
+            [mode, ca] => {
+                auth = Some(ClientAuth {
+                    mode: ClientAuthMode::from_arg(mode)?,
+                    ca: ca.to_string(),
+                });
+            }
+            _ => return None,
+        }
+    }
+    Some(auth)
+}
+
+/// Options block of a `tls_options` directive
+fn tls_options(node: &kdl::KdlNode) -> Option<TlsOptions> {
+    let mut options = TlsOptions::default();
+    for option in node.children().map(|c| c.nodes()).unwrap_or(&[]) {
+        let args = get_string_args(option);
+        let names = |name: fn(&str) -> Option<&'static str>| {
+            let names: Option<Vec<String>> = args
+                .iter()
+                .map(|arg| name(arg).map(|n| n.to_string()))
+                .collect();
+            names.filter(|names| !names.is_empty())
+        };
+        match option.name().value() {
+            "min_version" => options.min_version = Some(TlsVersion::from_arg(args.first()?)?),
+            "cipher_suites" => options.cipher_suites = Some(names(cipher_suite_name)?),
+            "kx_groups" => options.kx_groups = Some(names(kx_group_name)?),
+            "alpn" => {
+                let known = |p: &&str| ALPN_PROTOCOLS.contains(p);
+                if args.is_empty() || !args.iter().all(known) {
+                    return None;
+                }
+                options.alpn = Some(args.iter().map(|p| p.to_string()).collect());
+            }
+            "session_tickets" => {
+                options.session_tickets = Some(option.entries().first()?.value().as_bool()?);
+            }
+            "session_cache" => {
+                let size = get_int_args(option).first().copied()?;
+                options.session_cache = Some(usize::try_from(size).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
 fn get_string_args<'a>(node: &'a kdl::KdlNode) -> Vec<&'a str> {
     node.entries()
         .iter()
@@ -127,11 +487,35 @@ fn get_string_args<'a>(node: &'a kdl::KdlNode) -> Vec<&'a str> {
         .collect::<Vec<&'a str>>()
 }
 
+/// A boolean option, a bare flag turns it on
+fn get_flag(node: &kdl::KdlNode) -> Option<bool> {
+    match node.entries().first() {
+        Some(entry) => entry.value().as_bool(),
+        None => Some(true),
+    }
+}
+
+fn get_int_args(node: &kdl::KdlNode) -> Vec<i64> {
+    node.entries()
+        .iter()
+        .filter_map(|e| e.value().as_i64())
+        .collect::<Vec<i64>>()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::build_config;
+    use crate::acme::{AcmeChallenge, AcmeOptions};
+    use crate::client_auth::{ClientAuth, ClientAuthMode};
+    use crate::config::{build_config, proxy_options, Directive};
+    use crate::encode::Encoding;
+    use crate::forwarded::HostHeader;
+    use crate::reverse_proxy::DEFAULT_POOL_IDLE_TIMEOUT;
+    use crate::tls::TlsVersion;
+    use crate::upstream::LbPolicy;
     use kdl::KdlDocument;
     use std::error::Error;
+    use std::path::Path;
+    use std::time::Duration;
 
     #[test]
     fn test_simple() -> Result<(), Box<dyn Error>> {
@@ -183,4 +567,446 @@ example.com {
 
         Ok(())
     }
+
+    #[test]
+    fn test_tls_auto() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    file_server
+    tls "auto"
+}
+"example.org:8443" {
+    file_server
+    tls "auto" {
+        email "admin@example.org"
+        ca "https://localhost:14000/dir"
+        ca_root "/etc/pebble/pebble.minica.pem"
+        storage "/var/lib/cblt"
+        challenge "tls-alpn-01"
+        http_port 5002
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let acme = |host: &str| {
+            config[host].iter().find_map(|d| match d {
+                Directive::TlsAuto { acme, .. } => Some(acme.clone()),
+                _ => None,
+            })
+        };
+        assert_eq!(acme("example.com"), Some(AcmeOptions::default()));
+        let options = acme("example.org:8443").unwrap();
+        assert_eq!(options.email.as_deref(), Some("admin@example.org"));
+        assert_eq!(options.ca, "https://localhost:14000/dir");
+        assert_eq!(
+            options.ca_root.as_deref(),
+            Some("/etc/pebble/pebble.minica.pem")
+        );
+        assert_eq!(options.storage, "/var/lib/cblt");
+        assert_eq!(options.challenge, AcmeChallenge::TlsAlpn01);
+        assert_eq!(options.http_port, 5002);
+
+        for invalid in [
+            r#"example.com { tls "auto" { challenge "dns-01"; }; }"#,
+            r#"example.com { tls "auto" { http_port 70000; }; }"#,
+            r#"example.com { tls "auto" { renew_before 30; }; }"#,
+            r#""*.example.com" { tls "auto"; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_auth() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+admin.example.com {
+    tls "/certs/admin.crt" "/certs/admin.key" {
+        client_auth "require" "/certs/clients-ca.pem"
+    }
+}
+example.com {
+    tls "auto" {
+        email "admin@example.com"
+        client_auth "request" "/certs/clients-ca.pem"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let client_auth = |host: &str| {
+            config[host].iter().find_map(|d| match d {
+                Directive::Tls { client_auth, .. } | Directive::TlsAuto { client_auth, .. } => {
+                    client_auth.clone()
+                }
+                _ => None,
+            })
+        };
+        assert_eq!(
+            client_auth("admin.example.com"),
+            Some(ClientAuth {
+                mode: ClientAuthMode::Require,
+                ca: "/certs/clients-ca.pem".to_string(),
+            })
+        );
+        assert_eq!(
+            client_auth("example.com").map(|auth| auth.mode),
+            Some(ClientAuthMode::Request)
+        );
+
+        for invalid in [
+            r#"example.com { tls "a.crt" "a.key" { client_auth "always" "ca.pem"; }; }"#,
+            r#"example.com { tls "a.crt" "a.key" { client_auth "require"; }; }"#,
+            r#"example.com { tls "a.crt" "a.key" { email "admin@example.com"; }; }"#,
+            r#""*" { tls "a.crt" "a.key" { client_auth "require" "ca.pem"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    tls "/certs/example.crt" "/certs/example.key"
+    tls_options {
+        min_version "1.3"
+        cipher_suites "tls13_aes_256_gcm_sha384" "TLS13_CHACHA20_POLY1305_SHA256"
+        kx_groups "X25519" "secp384r1"
+        alpn "http/1.1"
+        session_tickets true
+        session_cache 0
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let options = config["example.com"]
+            .iter()
+            .find_map(|d| match d {
+                Directive::TlsOptions { options } => Some(options.clone()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(options.min_version, Some(TlsVersion::Tls13));
+        assert_eq!(
+            options.cipher_suites,
+            Some(vec![
+                "TLS13_AES_256_GCM_SHA384".to_string(),
+                "TLS13_CHACHA20_POLY1305_SHA256".to_string()
+            ])
+        );
+        assert_eq!(
+            options.kx_groups,
+            Some(vec!["X25519".to_string(), "secp384r1".to_string()])
+        );
+        assert_eq!(options.alpn, Some(vec!["http/1.1".to_string()]));
+        assert_eq!(options.session_tickets, Some(true));
+        assert_eq!(options.session_cache, Some(0));
+
+        for invalid in [
+            r#"example.com { tls_options { min_version "1.1"; }; }"#,
+            r#"example.com { tls_options { cipher_suites "TLS_RSA_WITH_RC4_128_SHA"; }; }"#,
+            r#"example.com { tls_options { kx_groups; }; }"#,
+            r#"example.com { tls_options { alpn "spdy/3"; }; }"#,
+            r#"example.com { tls_options { session_tickets "yes"; }; }"#,
+            r#"example.com { tls_options { session_cache -1; }; }"#,
+            r#"example.com { tls_options { ocsp true; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_h2c_http3() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:8080" {
+    file_server
+    h2c
+}
+"*:8081" {
+    file_server
+    h2c false
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let h2c = |host: &str| config[host].iter().any(|d| matches!(d, Directive::H2c));
+        assert!(h2c("*:8080"));
+        assert!(!h2c("*:8081"));
+
+        let doc: KdlDocument = r#""*:8080" { h2c "yes"; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        let doc: KdlDocument = r#"example.com { http3; tls "a.crt" "a.key"; }"#.parse()?;
+        let config = build_config(&doc)?;
+        assert!(config["example.com"]
+            .iter()
+            .any(|d| matches!(d, Directive::Http3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_redirect() -> Result<(), Box<dyn Error>> {
+        let doc: KdlDocument =
+            r#"example.com { tls "a.crt" "a.key"; http_redirect false; }"#.parse()?;
+        let config = build_config(&doc)?;
+        assert!(config["example.com"]
+            .iter()
+            .any(|d| matches!(d, Directive::NoHttpRedirect)));
+        let doc: KdlDocument = r#"example.com { tls "a.crt" "a.key"; http_redirect; }"#.parse()?;
+        let config = build_config(&doc)?;
+        assert!(!config["example.com"]
+            .iter()
+            .any(|d| matches!(d, Directive::NoHttpRedirect)));
+
+        let doc: KdlDocument = r#"example.com { http_redirect "no"; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_connections() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server
+    max_connections 512
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = config.get("*:80").unwrap();
+        assert!(directives
+            .iter()
+            .any(|d| matches!(d, Directive::MaxConnections { limit: 512 })));
+
+        let cblt_file = r#"
+"*:80" {
+    max_connections 0
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_alive() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server
+    keep_alive {
+        timeout 30
+        requests 100
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = config.get("*:80").unwrap();
+        assert!(directives.iter().any(|d| matches!(
+            d,
+            Directive::KeepAlive {
+                timeout: Some(t),
+                requests: Some(100)
+            } if t.as_secs() == 30
+        )));
+
+        let cblt_file = r#"
+"*:80" {
+    keep_alive {
+        timeout 0
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_server_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server {
+        follow_symlinks false
+        browse
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = config.get("*:80").unwrap();
+        let options = directives
+            .iter()
+            .find_map(|d| match d {
+                Directive::FileServer { options } => Some(options),
+                _ => None,
+            })
+            .unwrap();
+        assert!(!options.follow_symlinks);
+        assert!(options.browse);
+        assert!(!options.show_hidden);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_options() -> Result<(), Box<dyn Error>> {
+        let doc: KdlDocument = r#"
+reverse_proxy "/api/*" "https://10.8.0.3" "https://10.8.0.4" {
+    lb_policy "cookie" "srv"
+    lb_retries 1
+    health_uri "/health"
+    health_interval 5
+    health_status 204
+    max_fails 3
+    fail_duration 30
+    trusted_proxies "10.0.0.0/8" "192.168.1.1"
+    host_header "upstream"
+    strip_prefix "/api"
+    rewrite "^/v1/(.*)$" "/$1"
+    tunnel_idle_timeout 3600
+    pool_max_idle 8
+    connect_timeout 3
+    read_timeout 60
+    tls_insecure_skip_verify
+}
+            "#
+        .parse()?;
+        let options = proxy_options(&doc.nodes()[0]).unwrap();
+        assert_eq!(
+            options.lb_policy,
+            LbPolicy::Cookie {
+                name: "srv".to_string()
+            }
+        );
+        assert_eq!(options.lb_retries, Some(1));
+        assert_eq!(options.health.uri.as_deref(), Some("/health"));
+        assert_eq!(options.health.interval, Duration::from_secs(5));
+        assert_eq!(options.health.status, Some(204));
+        assert_eq!(options.health.passes, 1);
+        assert_eq!(options.health.max_fails, 3);
+        assert_eq!(options.health.fail_duration, Some(Duration::from_secs(30)));
+        assert_eq!(options.forwarded.trusted_proxies.len(), 2);
+        assert_eq!(options.forwarded.host, HostHeader::Upstream);
+        assert_eq!(options.rewrite.apply("/api/v1/users"), "/users");
+        assert_eq!(options.tunnel_idle_timeout, Duration::from_secs(3600));
+        assert_eq!(options.pool_max_idle, 8);
+        assert_eq!(options.pool_idle_timeout, DEFAULT_POOL_IDLE_TIMEOUT);
+        assert_eq!(options.connect_timeout, Duration::from_secs(3));
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(options.tls_ca, None);
+        assert!(options.tls_insecure_skip_verify);
+
+        let doc: KdlDocument = r#"reverse_proxy "/*" "http://a" { connect_timeout 0; }"#.parse()?;
+        assert_eq!(proxy_options(&doc.nodes()[0]), None);
+        let doc: KdlDocument =
+            r#"reverse_proxy "/*" "http://a" { lb_policy "fastest"; }"#.parse()?;
+        assert_eq!(proxy_options(&doc.nodes()[0]), None);
+        let doc: KdlDocument =
+            r#"reverse_proxy "/*" "http://a" { trusted_proxies "10.0.0.0/40"; }"#.parse()?;
+        assert_eq!(proxy_options(&doc.nodes()[0]), None);
+        let doc: KdlDocument = r#"reverse_proxy "/*" "http://a" { rewrite "(" "/"; }"#.parse()?;
+        assert_eq!(proxy_options(&doc.nodes()[0]), None);
+
+        let cblt_file = r#"
+"*:80" {
+    reverse_proxy "/*" "https://10.8.0.3" {
+        tls_ca "/nonexistent/ca.pem"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mime_types() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server
+    mime_types {
+        wasm "application/wasm"
+        ".log" "text/plain; charset=utf-8"
+        default "text/plain"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = config.get("*:80").unwrap();
+        let types = directives
+            .iter()
+            .find_map(|d| match d {
+                Directive::MimeTypes { types } => Some(types),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            types.lookup(Path::new("access.log")),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(types.lookup(Path::new("LICENSE")), "text/plain");
+
+        let doc: KdlDocument = r#""*:80" { mime_types { txt "text/plain\n"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server
+    encode "br" "gzip" {
+        minimum_length 1024
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = config.get("*:80").unwrap();
+        let encode = directives
+            .iter()
+            .find_map(|d| match d {
+                Directive::Encode { encode } => Some(encode),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(encode.encodings, vec![Encoding::Brotli, Encoding::Gzip]);
+        assert_eq!(encode.minimum_length, 1024);
+
+        let cblt_file = r#"
+"*:80" {
+    encode "deflate"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
 }
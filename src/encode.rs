@@ -0,0 +1,316 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+
+/// Responses smaller than this aren't worth compressing on the fly
+pub const DEFAULT_MINIMUM_LENGTH: u64 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Zstd,
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "zstd" => Some(Encoding::Zstd),
+            "br" => Some(Encoding::Brotli),
+            "gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// `Content-Encoding` token
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Suffix of the precompressed sidecar file, e.g. `app.js.br`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zst",
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    /// Wraps `reader` so it yields the compressed stream
+    pub fn encoder<R>(&self, reader: R) -> Pin<Box<dyn AsyncRead + Send>>
+    where
+        R: AsyncBufRead + Send + 'static,
+    {
+        match self {
+            Encoding::Zstd => Box::pin(ZstdEncoder::new(reader)),
+            // Brotli defaults to its slowest level, meant for precompressing ahead of time
+            Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+            Encoding::Gzip => Box::pin(GzipEncoder::new(reader)),
+        }
+    }
+
+    pub async fn encode_bytes(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut encoded = Vec::with_capacity(body.len() / 2);
+        self.encoder(std::io::Cursor::new(body.to_vec()))
+            .read_to_end(&mut encoded)
+            .await?;
+        Ok(encoded)
+    }
+}
+
+/// The host's `encode` directive: enabled encodings in order of preference.
+#[derive(Debug, Clone)]
+pub struct Encode {
+    pub encodings: Vec<Encoding>,
+    pub minimum_length: u64,
+}
+
+impl Encode {
+    /// Encodings acceptable to the client, best first: by the client's q-value, then by
+    /// the order they are listed in the `encode` directive.
+    pub fn negotiate(&self, accept_encoding: &str) -> Vec<Encoding> {
+        let mut accepted: Vec<(Encoding, f32)> = self
+            .encodings
+            .iter()
+            .filter_map(|encoding| {
+                let q = accept_encoding_q(accept_encoding, encoding.name());
+                if q > 0.0 {
+                    Some((*encoding, q))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // Stable sort keeps the configured order among equal q-values
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        accepted.into_iter().map(|(encoding, _)| encoding).collect()
+    }
+
+    /// Encodings acceptable for `request`, best first
+    pub fn accepted<B>(&self, request: &Request<B>) -> Vec<Encoding> {
+        match request.headers().get(ACCEPT_ENCODING) {
+            Some(accept_encoding) => self.negotiate(accept_encoding.to_str().unwrap_or("")),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether a response of this type and length should be compressed on the fly
+    pub fn should_compress(&self, content_type: &str, length: Option<u64>) -> bool {
+        is_compressible(content_type) && length.is_none_or(|len| len >= self.minimum_length)
+    }
+
+    /// Decides whether `response` gets compressed on the fly and if so prepares its headers
+    /// (`Content-Encoding`, `Vary`, `ETag`, no `Content-Length`); the caller encodes the body.
+    pub fn prepare_response<B>(
+        &self,
+        request: &Request<Vec<u8>>,
+        response: &mut Response<B>,
+        length: Option<u64>,
+    ) -> Option<Encoding> {
+        if response.status() != StatusCode::OK || request.method() == Method::HEAD {
+            return None;
+        }
+        let content_type = response.headers().get(CONTENT_TYPE)?.to_str().unwrap_or("");
+        if !self.should_compress(content_type, length) {
+            return None;
+        }
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        if response.headers().contains_key(CONTENT_ENCODING) {
+            return None;
+        }
+        let encoding = self.accepted(request).first().copied()?;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        headers.remove(CONTENT_LENGTH);
+        if let Some(etag) = headers.get(ETAG).and_then(|e| e.to_str().ok()) {
+            if let Ok(etag) = HeaderValue::from_str(&encoded_etag(etag, encoding)) {
+                headers.insert(ETAG, etag);
+            }
+        }
+        Some(encoding)
+    }
+
+    /// Compresses a fully buffered response if the client accepts it
+    /// and it isn't encoded already.
+    pub async fn encode_response(
+        &self,
+        request: &Request<Vec<u8>>,
+        response: &mut Response<Vec<u8>>,
+    ) {
+        let length = Some(response.body().len() as u64);
+        let Some(encoding) = self.prepare_response(request, response, length) else {
+            return;
+        };
+        match encoding.encode_bytes(response.body()).await {
+            Ok(encoded) => *response.body_mut() = encoded,
+            Err(_) => {
+                response.headers_mut().remove(CONTENT_ENCODING);
+            }
+        }
+    }
+}
+
+/// The compressed bytes differ from the original ones, and may differ between compressor
+/// versions, so the entity tag becomes a weak one specific to the encoding
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    format!(
+        "W/{}-{}\"",
+        etag.trim_start_matches("W/").trim_end_matches('"'),
+        encoding.name()
+    )
+}
+
+/// q-value the client gives `name` in `Accept-Encoding`, falling back to `*`
+fn accept_encoding_q(accept_encoding: &str, name: &str) -> f32 {
+    let mut wildcard = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let q = params
+            .find_map(|p| {
+                let (key, value) = p.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("q") {
+                    value.trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case(name) {
+            return q;
+        }
+        if coding == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+/// Text-like types that shrink well; images, video and archives are already compressed
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/yaml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+                | "image/bmp"
+                | "font/ttf"
+                | "font/otf"
+                | "application/vnd.ms-fontobject"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encode::{encoded_etag, is_compressible, Encode, Encoding};
+    use http::{Request, Response};
+    use std::error::Error;
+
+    fn encode() -> Encode {
+        Encode {
+            encodings: vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip],
+            minimum_length: 512,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() -> Result<(), Box<dyn Error>> {
+        let encode = encode();
+        assert_eq!(
+            encode.negotiate("gzip, deflate, br, zstd"),
+            vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            encode.negotiate("gzip;q=1.0, br;q=0.5"),
+            vec![Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(
+            encode.negotiate("*;q=0.1, gzip"),
+            vec![Encoding::Gzip, Encoding::Zstd, Encoding::Brotli]
+        );
+        assert_eq!(encode.negotiate("br;q=0, gzip;q=0"), vec![]);
+        assert_eq!(encode.negotiate("identity"), vec![]);
+        assert_eq!(encode.negotiate(""), vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_should_compress() -> Result<(), Box<dyn Error>> {
+        let encode = encode();
+        assert!(encode.should_compress("text/html; charset=utf-8", Some(4096)));
+        assert!(encode.should_compress("application/json", None));
+        assert!(!encode.should_compress("text/css", Some(100)));
+        assert!(!encode.should_compress("image/png", Some(4096)));
+        assert!(is_compressible("application/manifest+json; charset=utf-8"));
+        assert!(!is_compressible("video/mp4"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_response() -> Result<(), Box<dyn Error>> {
+        let encode = encode();
+        let request = Request::builder()
+            .uri("/api")
+            .header("Accept-Encoding", "gzip")
+            .body(Vec::new())?;
+        let mut response = Response::builder()
+            .header("Content-Type", "application/json")
+            .header("Content-Length", "2000")
+            .header("ETag", "\"v1\"")
+            .body(b"[1]".repeat(1000).to_vec())?;
+        encode.encode_response(&request, &mut response).await;
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        assert_eq!(response.headers()["Vary"], "Accept-Encoding");
+        assert_eq!(response.headers()["ETag"], "W/\"v1-gzip\"");
+        assert!(!response.headers().contains_key("Content-Length"));
+        assert!(response.body().len() < 3000);
+
+        // Already encoded upstream responses are left alone
+        let mut response = Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Content-Encoding", "br")
+            .body(b"x".repeat(1000).to_vec())?;
+        encode.encode_response(&request, &mut response).await;
+        assert_eq!(response.headers()["Content-Encoding"], "br");
+        assert_eq!(response.body().len(), 1000);
+
+        assert_eq!(encoded_etag("W/\"abc\"", Encoding::Brotli), "W/\"abc-br\"");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_bytes() -> Result<(), Box<dyn Error>> {
+        let body = "hello ".repeat(1000);
+        for encoding in [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip] {
+            let encoded = encoding.encode_bytes(body.as_bytes()).await?;
+            assert!(!encoded.is_empty() && encoded.len() < body.len());
+        }
+
+        Ok(())
+    }
+}
@@ -1,13 +1,49 @@
+use crate::browse;
+use crate::conditional::Validators;
+use crate::encode::{encoded_etag, Encode, Encoding};
+use crate::mime_types::MimeTypes;
+use crate::range::{parse_range, ByteRanges, ConcatReader};
 use crate::response::{error_response, send_response, send_response_file};
-use http::{Request, Response, StatusCode};
-use std::path::PathBuf;
+use http::header::{CONTENT_ENCODING, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, VARY};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
+use log::debug;
+use percent_encoding::percent_decode_str;
+use std::fs::Metadata;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
 use tracing::instrument;
 
+/// Options from the `file_server` block
+#[derive(Debug, Clone)]
+pub struct FileServerOptions {
+    pub follow_symlinks: bool,
+    /// List directories that have no index.html
+    pub browse: bool,
+    /// Include dotfiles in listings
+    pub show_hidden: bool,
+}
+
+impl Default for FileServerOptions {
+    fn default() -> Self {
+        FileServerOptions {
+            follow_symlinks: true,
+            browse: false,
+            show_hidden: false,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
 pub async fn directive<S>(
     root_path: &Option<String>,
+    options: &FileServerOptions,
+    mime_types: &MimeTypes,
+    encode: Option<&Encode>,
     request: &Request<Vec<u8>>,
     handled: &mut bool,
     socket: &mut S,
@@ -16,18 +52,153 @@ pub async fn directive<S>(
     S: AsyncWriteExt + Unpin,
 {
     if let Some(root) = root_path {
-        let mut file_path = PathBuf::from(root);
-        file_path.push(request.uri().path().trim_start_matches('/'));
+        let mut file_path = match resolve_path(Path::new(root), request.uri().path()) {
+            Ok(file_path) => file_path,
+            Err(status) => {
+                let response = error_response(status);
+                let _ = send_response(&mut *socket, response, req_opt).await;
+                *handled = true;
+                return;
+            }
+        };
 
         if file_path.is_dir() {
-            file_path.push("index.html");
+            let index = file_path.join("index.html");
+            if options.browse && !index.is_file() {
+                if !options.follow_symlinks && escapes_root(Path::new(root), &file_path).await {
+                    let response = error_response(StatusCode::FORBIDDEN);
+                    let _ = send_response(&mut *socket, response, req_opt).await;
+                } else {
+                    browse::directive(
+                        &file_path,
+                        options.show_hidden,
+                        encode,
+                        request,
+                        socket,
+                        req_opt,
+                    )
+                    .await;
+                }
+                *handled = true;
+                return;
+            }
+            file_path = index;
+        }
+
+        if !options.follow_symlinks && escapes_root(Path::new(root), &file_path).await {
+            let response = error_response(StatusCode::FORBIDDEN);
+            let _ = send_response(&mut *socket, response, req_opt).await;
+            *handled = true;
+            return;
         }
 
-        match File::open(&file_path).await {
-            Ok(file) => {
-                let content_length = file_size(&file).await;
-                let response = file_response(file, content_length);
-                let _ = send_response_file(socket, response, req_opt).await;
+        let content_type = mime_types.lookup(&file_path);
+        let accepted = encode.map(|e| e.accepted(request)).unwrap_or_default();
+        let vary = encode.is_some();
+
+        match open_file(
+            Path::new(root),
+            &file_path,
+            options.follow_symlinks,
+            &accepted,
+        )
+        .await
+        {
+            Ok((mut file, precompressed)) => {
+                let metadata = file_metadata(&file).await;
+                let size = metadata.len();
+                let mut validators = Validators::from_metadata(&metadata);
+                // Without a precompressed file, compress on the fly. The length isn't known
+                // up front so the body is sent chunked, which needs an HTTP/1.1 client
+                let on_the_fly = match encode {
+                    Some(encode)
+                        if precompressed.is_none()
+                            && request.version() == Version::HTTP_11
+                            && encode.should_compress(content_type, Some(size)) =>
+                    {
+                        accepted.first().copied()
+                    }
+                    _ => None,
+                };
+                if let Some(encoding) = on_the_fly {
+                    validators.etag = encoded_etag(&validators.etag, encoding);
+                }
+                let encoding = precompressed.or(on_the_fly);
+
+                if validators.not_modified(request) {
+                    let mut response = Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(Vec::new())
+                        .unwrap();
+                    set_representation(response.headers_mut(), &validators, encoding, vary);
+                    let _ = send_response(socket, response, req_opt).await;
+                    *handled = true;
+                    return;
+                }
+                let result = if let Some(encoding) = on_the_fly {
+                    // Ranges of the compressed stream can't be served, send all of it
+                    let body = encoding.encoder(BufReader::new(file));
+                    let mut response = encoded_response(body, content_type);
+                    set_representation(response.headers_mut(), &validators, encoding, vary);
+                    send_response_file(socket, response, req_opt).await
+                } else {
+                    match requested_ranges(request, &metadata, &validators) {
+                        ByteRanges::Full => {
+                            let mut response = file_response(file, size, content_type);
+                            set_representation(response.headers_mut(), &validators, encoding, vary);
+                            send_response_file(socket, response, req_opt).await
+                        }
+                        ByteRanges::Partial(ranges) if ranges.len() == 1 => {
+                            let (first, last) = ranges[0];
+                            match file.seek(SeekFrom::Start(first)).await {
+                                Ok(_) => {
+                                    let body = file.take(last - first + 1);
+                                    let mut response =
+                                        range_response(body, first, last, size, content_type);
+                                    set_representation(
+                                        response.headers_mut(),
+                                        &validators,
+                                        encoding,
+                                        vary,
+                                    );
+                                    send_response_file(socket, response, req_opt).await
+                                }
+                                Err(err) => Err(err.into()),
+                            }
+                        }
+                        ByteRanges::Partial(ranges) => {
+                            let served_path = match precompressed {
+                                Some(encoding) => sidecar_path(&file_path, encoding),
+                                None => file_path.clone(),
+                            };
+                            match multipart_response(&served_path, &ranges, size, content_type)
+                                .await
+                            {
+                                Ok(mut response) => {
+                                    set_representation(
+                                        response.headers_mut(),
+                                        &validators,
+                                        encoding,
+                                        vary,
+                                    );
+                                    send_response_file(socket, response, req_opt).await
+                                }
+                                Err(err) => Err(err.into()),
+                            }
+                        }
+                        ByteRanges::Unsatisfiable => {
+                            let mut response = error_response(StatusCode::RANGE_NOT_SATISFIABLE);
+                            response.headers_mut().insert(
+                                "Content-Range",
+                                format!("bytes */{}", size).parse().unwrap(),
+                            );
+                            send_response(socket, response, req_opt).await
+                        }
+                    }
+                };
+                if let Err(err) = result {
+                    debug!("Error: {}", err);
+                }
                 *handled = true;
                 return;
             }
@@ -46,17 +217,265 @@ pub async fn directive<S>(
     }
 }
 
+/// Maps a request path onto a file under `root`.
+///
+/// The path is percent-decoded once and then split into segments; any segment that could
+/// leave `root` (`..`, NUL bytes, drive prefixes or embedded separators) is refused with 403.
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
-async fn file_size(file: &File) -> u64 {
-    let metadata = file.metadata().await.unwrap();
-    metadata.len()
+fn resolve_path(root: &Path, uri_path: &str) -> Result<PathBuf, StatusCode> {
+    let decoded = percent_decode_str(uri_path)
+        .decode_utf8()
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+
+    let mut file_path = root.to_path_buf();
+    for segment in decoded.split(['/', '\\']) {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        if segment.contains('\0') {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => file_path.push(segment),
+            _ => return Err(StatusCode::FORBIDDEN),
+        }
+    }
+    Ok(file_path)
+}
+
+/// Whether `file_path`, once symlinks are resolved, lies outside `root`.
+/// Paths that don't exist are left for the caller to report as 404.
+async fn escapes_root(root: &Path, file_path: &Path) -> bool {
+    let Ok(root) = tokio::fs::canonicalize(root).await else {
+        return true;
+    };
+    match tokio::fs::canonicalize(file_path).await {
+        Ok(real_path) => !real_path.starts_with(root),
+        Err(_) => false,
+    }
 }
 
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
-fn file_response(file: File, content_length: u64) -> Response<File> {
+async fn file_metadata(file: &File) -> Metadata {
+    file.metadata().await.unwrap()
+}
+
+/// The byte ranges to send for a GET request, honoring `Range` only while `If-Range` holds.
+fn requested_ranges(
+    request: &Request<Vec<u8>>,
+    metadata: &Metadata,
+    validators: &Validators,
+) -> ByteRanges {
+    if request.method() != Method::GET {
+        return ByteRanges::Full;
+    }
+    let Some(range) = request.headers().get(RANGE).and_then(|v| v.to_str().ok()) else {
+        return ByteRanges::Full;
+    };
+    if let Some(if_range) = request.headers().get(IF_RANGE) {
+        let if_range = if_range.to_str().unwrap_or("");
+        if !validators.if_range_matches(if_range) {
+            return ByteRanges::Full;
+        }
+    }
+    parse_range(range, metadata.len())
+}
+
+fn set_representation(
+    headers: &mut HeaderMap,
+    validators: &Validators,
+    encoding: impl Into<Option<Encoding>>,
+    vary: bool,
+) {
+    headers.insert(ETAG, validators.etag.parse().unwrap());
+    if let Some(last_modified) = &validators.last_modified {
+        headers.insert(LAST_MODIFIED, last_modified.parse().unwrap());
+    }
+    if let Some(encoding) = encoding.into() {
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    }
+    if vary {
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// Opens the file to serve: the precompressed sidecar (`file.ext.br`, ...) of the best
+/// encoding the client accepts when there is one, the file itself otherwise.
+async fn open_file(
+    root: &Path,
+    file_path: &Path,
+    follow_symlinks: bool,
+    accepted: &[Encoding],
+) -> std::io::Result<(File, Option<Encoding>)> {
+    for &encoding in accepted {
+        let sidecar = sidecar_path(file_path, encoding);
+        if !follow_symlinks && escapes_root(root, &sidecar).await {
+            continue;
+        }
+        if let Ok(file) = File::open(&sidecar).await {
+            return Ok((file, Some(encoding)));
+        }
+    }
+    Ok((File::open(file_path).await?, None))
+}
+
+fn sidecar_path(file_path: &Path, encoding: Encoding) -> PathBuf {
+    let mut sidecar = file_path.as_os_str().to_os_string();
+    sidecar.push(".");
+    sidecar.push(encoding.extension());
+    PathBuf::from(sidecar)
+}
+
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+fn file_response(file: File, content_length: u64, content_type: &str) -> Response<File> {
     Response::builder()
         .status(StatusCode::OK)
+        .header("Content-Type", content_type)
         .header("Content-Length", content_length)
+        .header("Accept-Ranges", "bytes")
         .body(file)
         .unwrap()
 }
+
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+fn encoded_response<B>(body: B, content_type: &str) -> Response<B> {
+    // No Content-Length: the compressed size is only known once it's sent
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(body)
+        .unwrap()
+}
+
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+fn range_response<B>(body: B, first: u64, last: u64, size: u64, content_type: &str) -> Response<B> {
+    Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Type", content_type)
+        .header("Content-Length", last - first + 1)
+        .header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", first, last, size),
+        )
+        .header("Accept-Ranges", "bytes")
+        .body(body)
+        .unwrap()
+}
+
+/// Builds a `multipart/byteranges` response, each part read from its own handle on the file.
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+async fn multipart_response(
+    file_path: &Path,
+    ranges: &[(u64, u64)],
+    size: u64,
+    content_type: &str,
+) -> std::io::Result<Response<ConcatReader>> {
+    let boundary = multipart_boundary();
+    let mut parts: Vec<Pin<Box<dyn AsyncRead + Send>>> = Vec::with_capacity(ranges.len() * 2 + 1);
+    let mut content_length = 0;
+    for &(first, last) in ranges {
+        let part_header = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, first, last, size
+        );
+        let mut file = File::open(file_path).await?;
+        file.seek(SeekFrom::Start(first)).await?;
+        content_length += part_header.len() as u64 + last - first + 1;
+        parts.push(Box::pin(Cursor::new(part_header.into_bytes())));
+        parts.push(Box::pin(file.take(last - first + 1)));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    content_length += closing.len() as u64;
+    parts.push(Box::pin(Cursor::new(closing.into_bytes())));
+
+    Ok(Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .header("Content-Length", content_length)
+        .header("Accept-Ranges", "bytes")
+        .body(ConcatReader::new(parts))
+        .unwrap())
+}
+
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("cblt{:x}{:x}", nanos, std::process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_server::{escapes_root, resolve_path};
+    use http::StatusCode;
+    use std::error::Error;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_resolve_path() -> Result<(), Box<dyn Error>> {
+        let root = Path::new("/srv/www");
+        let allowed = [
+            ("/", "/srv/www"),
+            ("/index.html", "/srv/www/index.html"),
+            ("/a/./b.css", "/srv/www/a/b.css"),
+            ("//etc/passwd", "/srv/www/etc/passwd"),
+            ("/my%20file.txt", "/srv/www/my file.txt"),
+            // Decoded only once: "%2e%2e" is a literal file name here
+            ("/%252e%252e/secret", "/srv/www/%2e%2e/secret"),
+            ("/a..b/c", "/srv/www/a..b/c"),
+        ];
+        for (uri_path, expected) in allowed {
+            assert_eq!(resolve_path(root, uri_path), Ok(PathBuf::from(expected)));
+        }
+
+        let attacks = [
+            "/../../etc/passwd",
+            "/..",
+            "/static/../../etc/passwd",
+            "/%2e%2e/%2e%2e/etc/passwd",
+            "/%2E%2E%2Fetc%2Fpasswd",
+            "/.%2e/etc/passwd",
+            "/static/..%2f..%2fetc/passwd",
+            "/..%5c..%5cetc%5cpasswd",
+            "/static\\..\\..\\etc",
+            "/index.html%00.png",
+            "/a/%00/b",
+            "/%ff%fe",
+        ];
+        for uri_path in attacks {
+            assert_eq!(
+                resolve_path(root, uri_path),
+                Err(StatusCode::FORBIDDEN),
+                "{}",
+                uri_path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_escapes_root() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-escapes-root-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(dir.join("secret.txt"), "secret")?;
+        std::fs::write(root.join("public.txt"), "public")?;
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("leak.txt"))?;
+        std::os::unix::fs::symlink(root.join("public.txt"), root.join("alias.txt"))?;
+
+        assert!(!escapes_root(&root, &root.join("public.txt")).await);
+        assert!(!escapes_root(&root, &root.join("alias.txt")).await);
+        assert!(escapes_root(&root, &root.join("leak.txt")).await);
+        assert!(!escapes_root(&root, &root.join("missing.txt")).await);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
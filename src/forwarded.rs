@@ -0,0 +1,295 @@
+use crate::client_auth::ClientCert;
+use crate::hop_by_hop::strip_hop_by_hop;
+use http::header::{FORWARDED, HOST};
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderName, HeaderValue, Request};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+pub const X_CLIENT_CERT_SUBJECT: HeaderName = HeaderName::from_static("x-client-cert-subject");
+pub const X_CLIENT_CERT_SAN: HeaderName = HeaderName::from_static("x-client-cert-san");
+
+/// `Host` sent to the upstream
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    /// The one the client sent
+    Preserve,
+    /// The host of the upstream URL
+    Upstream,
+    Custom(String),
+}
+
+impl HostHeader {
+    pub fn from_arg(arg: &str) -> HostHeader {
+        match arg {
+            "preserve" => HostHeader::Preserve,
+            "upstream" => HostHeader::Upstream,
+            host => HostHeader::Custom(host.to_string()),
+        }
+    }
+}
+
+/// How a `reverse_proxy` directive tells upstreams about the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardedOptions {
+    /// Peers whose forwarding headers are kept and appended to, those of anyone else
+    /// are replaced as they could be forged
+    pub trusted_proxies: Vec<IpNet>,
+    pub host: HostHeader,
+}
+
+impl Default for ForwardedOptions {
+    fn default() -> Self {
+        ForwardedOptions {
+            trusted_proxies: Vec::new(),
+            host: HostHeader::Preserve,
+        }
+    }
+}
+
+/// Parses a `trusted_proxies` entry, a CIDR range or a single address
+pub fn parse_trusted_proxy(arg: &str) -> Option<IpNet> {
+    arg.parse::<IpNet>()
+        .ok()
+        .or_else(|| arg.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Headers of `request` as sent to the upstream: the client's end-to-end ones with `Host`
+/// handled as configured, plus `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host`
+/// and `Forwarded`, and `X-Client-Cert-Subject` and `X-Client-Cert-San` for clients that
+/// authenticated with a certificate.
+pub fn upstream_headers(request: &Request<Vec<u8>>, options: &ForwardedOptions) -> HeaderMap {
+    let incoming = request.headers();
+    let peer = request.extensions().get::<SocketAddr>().map(|p| p.ip());
+    let trusted =
+        peer.is_some_and(|ip| options.trusted_proxies.iter().any(|net| net.contains(&ip)));
+    let proto = match request.extensions().get::<Scheme>() {
+        Some(scheme) if *scheme == Scheme::HTTPS => "https",
+        _ => "http",
+    };
+    let host = incoming
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    let mut headers = HeaderMap::with_capacity(incoming.len() + 4);
+    for (key, value) in incoming {
+        if key == HOST
+            || key == FORWARDED
+            || key == X_FORWARDED_FOR
+            || key == X_FORWARDED_PROTO
+            || key == X_FORWARDED_HOST
+            || key == X_CLIENT_CERT_SUBJECT
+            || key == X_CLIENT_CERT_SAN
+        {
+            continue;
+        }
+        headers.append(key, value.clone());
+    }
+    strip_hop_by_hop(&mut headers);
+
+    match &options.host {
+        HostHeader::Preserve => {
+            if let Some(host) = incoming.get(HOST) {
+                headers.insert(HOST, host.clone());
+            }
+        }
+        HostHeader::Upstream => {} // Filled in from the upstream URL
+        HostHeader::Custom(host) => {
+            if let Ok(host) = HeaderValue::from_str(host) {
+                headers.insert(HOST, host);
+            }
+        }
+    }
+
+    // Values set by a trusted proxy in front of us describe the original client
+    let prior = |name: &HeaderName| {
+        let values: Vec<&str> = incoming
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        if trusted && !values.is_empty() {
+            Some(values.join(", "))
+        } else {
+            None
+        }
+    };
+
+    let client = peer.map(|ip| ip.to_string()).unwrap_or_default();
+    let forwarded_for = match prior(&X_FORWARDED_FOR) {
+        Some(chain) => format!("{}, {}", chain, client),
+        None => client,
+    };
+    let forwarded_proto = prior(&X_FORWARDED_PROTO).unwrap_or_else(|| proto.to_string());
+    let forwarded_host = prior(&X_FORWARDED_HOST).unwrap_or_else(|| host.to_string());
+    let element = forwarded_element(peer, host, proto);
+    let forwarded = match prior(&FORWARDED) {
+        Some(chain) => format!("{}, {}", chain, element),
+        None => element,
+    };
+
+    for (name, value) in [
+        (X_FORWARDED_FOR, forwarded_for),
+        (X_FORWARDED_PROTO, forwarded_proto),
+        (X_FORWARDED_HOST, forwarded_host),
+        (FORWARDED, forwarded),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    }
+
+    let (subject, sans) = match request.extensions().get::<ClientCert>() {
+        Some(client) => (Some(client.subject.clone()), Some(client.sans.join(", "))),
+        None => (prior(&X_CLIENT_CERT_SUBJECT), prior(&X_CLIENT_CERT_SAN)),
+    };
+    for (name, value) in [(X_CLIENT_CERT_SUBJECT, subject), (X_CLIENT_CERT_SAN, sans)] {
+        if let Some(Ok(value)) = value.filter(|v| !v.is_empty()).map(HeaderValue::try_from) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// One hop of the RFC 7239 `Forwarded` header
+fn forwarded_element(peer: Option<IpAddr>, host: &str, proto: &str) -> String {
+    let node = match peer {
+        Some(IpAddr::V4(ip)) => ip.to_string(),
+        // IPv6 addresses contain colons, so they are bracketed and quoted
+        Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        None => "unknown".to_string(),
+    };
+    let mut element = format!("for={}", node);
+    if !host.is_empty() {
+        element.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+    }
+    element.push_str(&format!(";proto={}", proto));
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_auth::ClientCert;
+    use crate::forwarded::{parse_trusted_proxy, upstream_headers, ForwardedOptions, HostHeader};
+    use http::uri::Scheme;
+    use http::Request;
+    use std::error::Error;
+    use std::net::SocketAddr;
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+        let mut builder = Request::builder().uri("/api").header("Host", "example.com");
+        for (key, value) in headers {
+            builder = builder.header(*key, *value);
+        }
+        let mut request = builder.body(Vec::new()).unwrap();
+        request
+            .extensions_mut()
+            .insert(peer.parse::<SocketAddr>().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_untrusted() -> Result<(), Box<dyn Error>> {
+        let options = ForwardedOptions::default();
+        // Forged headers from an untrusted client are replaced
+        let req = request(
+            "203.0.113.7:5000",
+            &[
+                ("X-Forwarded-For", "1.2.3.4"),
+                ("X-Forwarded-Proto", "https"),
+                ("Forwarded", "for=1.2.3.4"),
+                ("X-Client-Cert-Subject", "CN=admin"),
+                ("Accept", "*/*"),
+                ("Connection", "keep-alive, X-Hop"),
+                ("X-Hop", "1"),
+            ],
+        );
+        let headers = upstream_headers(&req, &options);
+        assert!(!headers.contains_key("connection"));
+        assert!(!headers.contains_key("x-hop"));
+        assert_eq!(headers["host"], "example.com");
+        assert_eq!(headers["accept"], "*/*");
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.7;host=\"example.com\";proto=http"
+        );
+        assert!(!headers.contains_key("x-client-cert-subject"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trusted() -> Result<(), Box<dyn Error>> {
+        let options = ForwardedOptions {
+            trusted_proxies: vec![
+                parse_trusted_proxy("10.0.0.0/8").unwrap(),
+                parse_trusted_proxy("::1").unwrap(),
+            ],
+            host: HostHeader::Upstream,
+        };
+        let mut req = request(
+            "10.1.2.3:5000",
+            &[
+                ("X-Forwarded-For", "198.51.100.1"),
+                ("X-Forwarded-Proto", "https"),
+                ("Forwarded", "for=198.51.100.1;proto=https"),
+                ("X-Client-Cert-Subject", "CN=admin"),
+            ],
+        );
+        req.extensions_mut().insert(Scheme::HTTPS);
+        let headers = upstream_headers(&req, &options);
+        assert_eq!(headers["x-client-cert-subject"], "CN=admin");
+        assert!(!headers.contains_key("host"));
+        assert_eq!(headers["x-forwarded-for"], "198.51.100.1, 10.1.2.3");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=198.51.100.1;proto=https, for=10.1.2.3;host=\"example.com\";proto=https"
+        );
+
+        let req = request("[::1]:5000", &[]);
+        let options = ForwardedOptions {
+            host: HostHeader::from_arg("backend.internal"),
+            ..options
+        };
+        let headers = upstream_headers(&req, &options);
+        assert_eq!(headers["host"], "backend.internal");
+        assert_eq!(headers["x-forwarded-for"], "::1");
+        assert!(headers["forwarded"].to_str()?.starts_with("for=\"[::1]\";"));
+
+        assert_eq!(parse_trusted_proxy("10.0.0.0/33"), None);
+        assert_eq!(parse_trusted_proxy("proxy"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_cert() -> Result<(), Box<dyn Error>> {
+        let options = ForwardedOptions::default();
+        let mut req = request("203.0.113.7:5000", &[("X-Client-Cert-San", "DNS:forged")]);
+        req.extensions_mut().insert(ClientCert {
+            subject: "CN=alice, O=Example".to_string(),
+            sans: vec![
+                "email:alice@example.com".to_string(),
+                "DNS:alice.example.com".to_string(),
+            ],
+            ca: "ca.pem".to_string(),
+        });
+        let headers = upstream_headers(&req, &options);
+        assert_eq!(headers["x-client-cert-subject"], "CN=alice, O=Example");
+        assert_eq!(
+            headers["x-client-cert-san"],
+            "email:alice@example.com, DNS:alice.example.com"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use crate::upstream::{Upstream, Upstreams};
+use futures_util::future::join_all;
+use log::{error, info};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Health checking of the upstreams of a `reverse_proxy` directive.
+///
+/// Active checks probe `uri` on every upstream each `interval` and take an upstream out of
+/// rotation after `fails` failed probes in a row, back in after `passes` good ones.
+/// Passive checks count failed requests: `max_fails` in a row eject the upstream for
+/// `fail_duration`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthOptions {
+    /// Path probed by the active checks, they are off without it
+    pub uri: Option<String>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Expected status of a probe, any 2xx by default
+    pub status: Option<u16>,
+    pub passes: usize,
+    pub fails: usize,
+    pub max_fails: usize,
+    /// Passive checks are off without it
+    pub fail_duration: Option<Duration>,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        HealthOptions {
+            uri: None,
+            interval: DEFAULT_HEALTH_INTERVAL,
+            timeout: DEFAULT_HEALTH_TIMEOUT,
+            status: None,
+            passes: 1,
+            fails: 1,
+            max_fails: 1,
+            fail_duration: None,
+        }
+    }
+}
+
+/// Starts the active checks of `upstreams` in the background, if they are configured.
+pub fn spawn_checks(upstreams: Arc<Upstreams>) {
+    let Some(uri) = upstreams.health.uri.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let options = &upstreams.health;
+        // Consecutive outcomes of the opposite verdict, per upstream
+        let mut streaks = vec![0; upstreams.list.len()];
+        let mut interval = tokio::time::interval(options.interval);
+        loop {
+            interval.tick().await;
+            let probes = upstreams
+                .list
+                .iter()
+                .map(|upstream| probe(upstream, &uri, options));
+            let results = join_all(probes).await;
+            for ((upstream, streak), ok) in upstreams.list.iter().zip(&mut streaks).zip(results) {
+                let healthy = upstream.is_healthy();
+                if ok == healthy {
+                    *streak = 0;
+                    continue;
+                }
+                *streak += 1;
+                if healthy && *streak >= options.fails {
+                    error!("Upstream {} is down", upstream.url);
+                    upstream.set_healthy(false);
+                    *streak = 0;
+                } else if !healthy && *streak >= options.passes {
+                    info!("Upstream {} is back up", upstream.url);
+                    upstream.set_healthy(true);
+                    *streak = 0;
+                }
+            }
+        }
+    });
+}
+
+async fn probe(upstream: &Upstream, uri: &str, options: &HealthOptions) -> bool {
+    let result = upstream
+        .client
+        .get(format!("{}{}", upstream.url, uri))
+        .timeout(options.timeout)
+        .send()
+        .await;
+    match result {
+        Ok(resp) => match options.status {
+            Some(status) => resp.status().as_u16() == status,
+            None => resp.status().is_success(),
+        },
+        Err(_) => false,
+    }
+}
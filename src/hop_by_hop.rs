@@ -0,0 +1,74 @@
+use http::header::{
+    CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use http::{HeaderMap, HeaderName};
+
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+
+/// Headers that only concern a single connection (RFC 9110, section 7.6.1)
+const HOP_BY_HOP: [HeaderName; 9] = [
+    CONNECTION,
+    KEEP_ALIVE,
+    PROXY_CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Removes the hop-by-hop headers, along with those the `Connection` header lists,
+/// before a message is forwarded to the next hop.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hop_by_hop::strip_hop_by_hop;
+    use http::{HeaderMap, HeaderValue};
+    use std::error::Error;
+
+    #[test]
+    fn test_strip_hop_by_hop() -> Result<(), Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        for (key, value) in [
+            ("connection", "keep-alive, X-Trace, close"),
+            ("connection", "Upgrade"),
+            ("keep-alive", "timeout=5"),
+            ("proxy-connection", "keep-alive"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("te", "trailers"),
+            ("trailer", "Expires"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("x-trace", "1"),
+            ("content-type", "text/plain"),
+            ("content-length", "10"),
+            ("cache-control", "no-cache"),
+        ] {
+            headers.append(key, HeaderValue::from_static(value));
+        }
+        strip_hop_by_hop(&mut headers);
+
+        let mut left: Vec<&str> = headers.keys().map(|k| k.as_str()).collect();
+        left.sort_unstable();
+        assert_eq!(
+            left,
+            vec!["cache-control", "content-length", "content-type"]
+        );
+
+        Ok(())
+    }
+}
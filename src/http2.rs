@@ -0,0 +1,247 @@
+use crate::bridge::{pipeline, read_response, write_request, RequestBody};
+use crate::client_auth::ClientCert;
+use crate::Server;
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use http::{Method, Request, StatusCode};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// ALPN protocol id of HTTP/2 over TLS
+pub const H2_ALPN: &[u8] = b"h2";
+/// What a client speaking HTTP/2 with prior knowledge sends first
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const MAX_CONCURRENT_STREAMS: u32 = 128;
+
+/// Serves an HTTP/2 connection. Every stream goes through the pipeline as an HTTP/1.1
+/// request, so directives behave the same for both protocols, and the response is
+/// translated back into HTTP/2 frames.
+pub async fn serve<S>(
+    io: S,
+    peer: SocketAddr,
+    client: Option<ClientCert>,
+    server: Arc<Server>,
+) -> Result<(), h2::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake(io)
+        .await?;
+    // Cloned into every stream being served, so it counts them
+    let streams = Arc::new(());
+    let mut served = 0;
+    let mut closing = false;
+
+    loop {
+        match tokio::time::timeout(server.keep_alive_timeout, connection.accept()).await {
+            Ok(Some(Ok((request, respond)))) => {
+                let stream = streams.clone();
+                let client = client.clone();
+                let stream_server = server.clone();
+                tokio::spawn(async move {
+                    serve_stream(request, respond, peer, client, &stream_server).await;
+                    drop(stream);
+                });
+                served += 1;
+                // Streams already open are still served, the client opens new ones elsewhere
+                if served >= server.keep_alive_requests && !closing {
+                    closing = true;
+                    connection.graceful_shutdown();
+                }
+            }
+            // Clients going away without saying goodbye are nothing to report
+            Ok(Some(Err(err))) if err.is_io() => return Ok(()),
+            Ok(Some(Err(err))) => return Err(err),
+            Ok(None) => return Ok(()),
+            // Idle for too long
+            Err(_) => {
+                if Arc::strong_count(&streams) == 1 && !closing {
+                    closing = true;
+                    connection.graceful_shutdown();
+                }
+            }
+        }
+    }
+}
+
+/// Whether a plain connection starts with the HTTP/2 preface, which is left unread
+pub async fn is_h2c(stream: &TcpStream, timeout: Duration) -> bool {
+    let peek = async {
+        let mut buf = [0; PREFACE.len()];
+        loop {
+            let n = match stream.peek(&mut buf).await {
+                Ok(n) => n,
+                Err(_) => return false,
+            };
+            if n == 0 || buf[..n] != PREFACE[..n] {
+                return false;
+            }
+            if n == PREFACE.len() {
+                return true;
+            }
+            // The rest of the preface is still on its way
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(timeout, peek).await.unwrap_or(false)
+}
+
+async fn serve_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    peer: SocketAddr,
+    client: Option<ClientCert>,
+    server: &Server,
+) {
+    let (parts, body) = request.into_parts();
+    let head_only = parts.method == Method::HEAD;
+    let ((pipe_read, mut pipe_write), serving) = pipeline(peer, client, server);
+    let (_, _, sent) = tokio::join!(
+        serving,
+        write_request(&parts, body, &mut pipe_write),
+        send_response(pipe_read, &mut respond, head_only),
+    );
+    if sent.is_err() {
+        respond.send_reset(Reason::INTERNAL_ERROR);
+    }
+}
+
+impl RequestBody for RecvStream {
+    async fn next(&mut self) -> io::Result<Option<Bytes>> {
+        match self.data().await {
+            Some(Ok(data)) => {
+                let _ = self.flow_control().release_capacity(data.len());
+                Ok(Some(data))
+            }
+            Some(Err(err)) => Err(io::Error::other(err)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Translates the HTTP/1.1 response the pipeline writes into the stream's response
+async fn send_response<R>(
+    pipe: R,
+    respond: &mut SendResponse<Bytes>,
+    head_only: bool,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let (response, mut body) = read_response(pipe).await?;
+    let status = response.status();
+    let end = head_only || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+    let mut stream = respond
+        .send_response(response, end)
+        .map_err(io::Error::other)?;
+    if end {
+        return Ok(());
+    }
+    while let Some(data) = body.next().await? {
+        send_data(&mut stream, data).await?;
+    }
+    stream
+        .send_data(Bytes::new(), true)
+        .map_err(io::Error::other)
+}
+
+/// Sends `data` as the client's flow control window allows
+async fn send_data(stream: &mut SendStream<Bytes>, mut data: Bytes) -> io::Result<()> {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(Ok(capacity)) => {
+                let chunk = data.split_to(capacity.min(data.len()));
+                stream.send_data(chunk, false).map_err(io::Error::other)?;
+            }
+            Some(Err(err)) => return Err(io::Error::other(err)),
+            None => return Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Directive;
+    use crate::file_server::FileServerOptions;
+    use crate::http2::serve;
+    use crate::Server;
+    use bytes::Bytes;
+    use http::{Method, Request, StatusCode};
+    use std::error::Error;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_streams() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-http2-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let content = "x".repeat(100_000);
+        std::fs::write(dir.join("big.txt"), &content)?;
+
+        let mut server = Server::new(8080, Default::default());
+        server.hosts.insert(
+            "*".to_string(),
+            vec![
+                Directive::Root {
+                    pattern: "*".to_string(),
+                    path: dir.to_string_lossy().into_owned(),
+                },
+                Directive::FileServer {
+                    options: FileServerOptions::default(),
+                },
+            ],
+        );
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let peer = "127.0.0.1:5000".parse()?;
+        tokio::spawn(serve(server_io, peer, None, Arc::new(server)));
+        let (client, connection) = h2::client::handshake(client_io).await?;
+        tokio::spawn(connection);
+
+        // Several streams at once on the one connection
+        let request = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(format!("http://example.com{}", path))
+                .body(())
+        };
+        let mut client = client.ready().await?;
+        let (big, _) = client.send_request(request(Method::GET, "/big.txt")?, true)?;
+        let mut client = client.ready().await?;
+        let (head, _) = client.send_request(request(Method::HEAD, "/big.txt")?, true)?;
+        let mut client = client.ready().await?;
+        let (missing, mut upload) =
+            client.send_request(request(Method::POST, "/missing.txt")?, false)?;
+        upload.send_data(Bytes::from_static(b"ignored"), true)?;
+
+        let response = big.await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-length"], "100000");
+        assert!(!response.headers().contains_key("connection"));
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while let Some(data) = body.data().await {
+            let data = data?;
+            body.flow_control().release_capacity(data.len())?;
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, content.as_bytes());
+
+        let response = head.await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.body().is_end_stream());
+
+        assert_eq!(missing.await?.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::bridge::{pipeline, read_response, write_request, RequestBody};
+use crate::tls::{SharedConfig, TlsConfigs, CERT_RELOAD_INTERVAL};
+use crate::Server;
+use bytes::{Buf, Bytes};
+use h3::error::Code;
+use h3::server::RequestStream;
+use http::{Method, Request, StatusCode};
+use log::{debug, error};
+use quinn::crypto::rustls::QuicServerConfig;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tokio::sync::Semaphore;
+use tokio_rustls::rustls;
+
+/// ALPN protocol id of HTTP/3
+const H3_ALPN: &[u8] = b"h3";
+
+/// Serves HTTP/3 on the UDP port of a TLS listener, with the certificates of its configs.
+/// Like HTTP/2 streams, requests go through the pipeline as HTTP/1.1 ones. Connections
+/// count against the listener's limit along with TCP ones.
+pub async fn serve(
+    server: Arc<Server>,
+    configs: SharedConfig,
+    connections: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error>> {
+    let mut current = configs.read().unwrap().clone();
+    let addr = SocketAddr::from(([0, 0, 0, 0], server.port));
+    let endpoint = quinn::Endpoint::server(quic_config(&current, &server)?, addr)?;
+
+    // Follow certificate reloads of the TCP listener
+    let reloading = endpoint.clone();
+    let reloading_server = server.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CERT_RELOAD_INTERVAL).await;
+            let latest = configs.read().unwrap().clone();
+            if Arc::ptr_eq(&latest, &current) {
+                continue;
+            }
+            current = latest;
+            match quic_config(&current, &reloading_server) {
+                Ok(config) => reloading.set_server_config(Some(config)),
+                Err(err) => error!("Error: {}", err),
+            }
+        }
+    });
+
+    accept(endpoint, server, connections).await;
+    Ok(())
+}
+
+async fn accept(endpoint: quinn::Endpoint, server: Arc<Server>, connections: Arc<Semaphore>) {
+    while let Some(incoming) = endpoint.accept().await {
+        // Refused clients go on over TCP, where they wait for their turn
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            incoming.refuse();
+            continue;
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(incoming, server).await {
+                debug!("HTTP/3 connection closed: {}", err);
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// QUIC config of the listener: its certificates, TLS 1.3 only and no client authentication
+fn quic_config(
+    configs: &TlsConfigs,
+    server: &Server,
+) -> Result<quinn::ServerConfig, Box<dyn Error>> {
+    let (tls, _) = configs.select(None);
+    let mut config = rustls::ServerConfig::builder_with_provider(tls.crypto_provider().clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_cert_resolver(tls.cert_resolver.clone());
+    config.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let mut quic = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(config)?));
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(server.keep_alive_timeout.try_into()?));
+    quic.transport_config(Arc::new(transport));
+    Ok(quic)
+}
+
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    server: Arc<Server>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let connection = incoming.await?;
+    let peer = connection.remote_address();
+    let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+    let mut served = 0;
+
+    while let Some(resolver) = connection.accept().await? {
+        let stream_server = server.clone();
+        tokio::spawn(async move {
+            match resolver.resolve_request().await {
+                Ok((request, stream)) => serve_stream(request, stream, peer, &stream_server).await,
+                Err(err) => debug!("HTTP/3 request failed: {}", err),
+            }
+        });
+        served += 1;
+        // Requests already sent are still served, the client sends new ones elsewhere
+        if served == server.keep_alive_requests {
+            connection.shutdown(0).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn serve_stream(
+    request: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    peer: SocketAddr,
+    server: &Server,
+) {
+    let (mut send, recv) = stream.split();
+    let (parts, _) = request.into_parts();
+    let head_only = parts.method == Method::HEAD;
+    let ((pipe_read, mut pipe_write), serving) = pipeline(peer, None, server);
+    let (_, _, sent) = tokio::join!(
+        serving,
+        write_request(&parts, recv, &mut pipe_write),
+        send_response(pipe_read, &mut send, head_only),
+    );
+    if sent.is_err() {
+        send.stop_stream(Code::H3_INTERNAL_ERROR);
+    }
+}
+
+impl RequestBody for RequestStream<h3_quinn::RecvStream, Bytes> {
+    async fn next(&mut self) -> io::Result<Option<Bytes>> {
+        match self.recv_data().await {
+            Ok(Some(mut data)) => Ok(Some(data.copy_to_bytes(data.remaining()))),
+            Ok(None) => Ok(None),
+            Err(err) => Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// Translates the HTTP/1.1 response the pipeline writes into the stream's response
+async fn send_response<R>(
+    pipe: R,
+    send: &mut RequestStream<h3_quinn::SendStream<Bytes>, Bytes>,
+    head_only: bool,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let (response, mut body) = read_response(pipe).await?;
+    let status = response.status();
+    send.send_response(response)
+        .await
+        .map_err(io::Error::other)?;
+    if !head_only && status != StatusCode::NO_CONTENT && status != StatusCode::NOT_MODIFIED {
+        while let Some(data) = body.next().await? {
+            send.send_data(data).await.map_err(io::Error::other)?;
+        }
+    }
+    send.finish().await.map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Directive;
+    use crate::file_server::FileServerOptions;
+    use crate::http3::{accept, quic_config, H3_ALPN};
+    use crate::{tls_config, Server};
+    use bytes::Buf;
+    use http::{Method, Request, StatusCode};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use std::error::Error;
+    use std::future::poll_fn;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+    use tokio_rustls::rustls;
+
+    #[tokio::test]
+    async fn test_http3() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-http3-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("index.html"), "hello")?;
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert = dir.join("localhost.crt").to_string_lossy().into_owned();
+        let key = dir.join("localhost.key").to_string_lossy().into_owned();
+        std::fs::write(&cert, certified.cert.pem())?;
+        std::fs::write(&key, certified.signing_key.serialize_pem())?;
+
+        let mut server = Server::new(443, Default::default());
+        server.http3 = true;
+        server.certs.insert("localhost".to_string(), (cert, key));
+        server.hosts.insert(
+            "localhost".to_string(),
+            vec![
+                Directive::Root {
+                    pattern: "*".to_string(),
+                    path: dir.to_string_lossy().into_owned(),
+                },
+                Directive::FileServer {
+                    options: FileServerOptions::default(),
+                },
+            ],
+        );
+        let server = Arc::new(server);
+        let config = quic_config(&*tls_config(&server)?, &server)?;
+        let endpoint = quinn::Endpoint::server(config.clone(), "127.0.0.1:0".parse()?)?;
+        let addr = endpoint.local_addr()?;
+        tokio::spawn(accept(
+            endpoint,
+            server.clone(),
+            Arc::new(Semaphore::new(1)),
+        ));
+        // A listener at its connection limit refuses new ones
+        let full = quinn::Endpoint::server(config, "127.0.0.1:0".parse()?)?;
+        let full_addr = full.local_addr()?;
+        tokio::spawn(accept(full, server, Arc::new(Semaphore::new(0))));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certified.cert.der().clone())?;
+        let mut tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls)?,
+        )));
+        assert!(client.connect(full_addr, "localhost")?.await.is_err());
+        let connection = client.connect(addr, "localhost")?.await?;
+        let (mut driver, mut send_request) =
+            h3::client::new(h3_quinn::Connection::new(connection)).await?;
+        tokio::spawn(async move { poll_fn(|cx| driver.poll_close(cx)).await });
+
+        for (method, path, status, body) in [
+            (Method::GET, "/index.html", StatusCode::OK, "hello"),
+            (Method::HEAD, "/index.html", StatusCode::OK, ""),
+            (
+                Method::GET,
+                "/missing.html",
+                StatusCode::NOT_FOUND,
+                "Not found",
+            ),
+        ] {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("https://localhost{}", path))
+                .body(())?;
+            let mut stream = send_request.send_request(request).await?;
+            stream.finish().await?;
+            let response = stream.recv_response().await?;
+            assert_eq!(response.status(), status);
+            assert!(response.headers().contains_key("alt-svc"));
+            let mut received = Vec::new();
+            while let Some(mut data) = stream.recv_data().await? {
+                received.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+            }
+            assert_eq!(received, body.as_bytes());
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
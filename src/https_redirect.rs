@@ -0,0 +1,132 @@
+use crate::acme::{AcmeState, HTTP_CHALLENGE_PATH};
+use crate::config::Directive;
+use crate::response::error_response;
+use crate::tls::host_name;
+use crate::Server;
+use http::header::LOCATION;
+use http::{Response, StatusCode, Uri};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Port of the plain listener TLS hosts redirect from
+pub const HTTP_PORT: u16 = 80;
+const HTTPS_PORT: u16 = 443;
+
+/// Serves the TLS hosts in `redirects` (host, HTTPS port) on the plain HTTP listener,
+/// which is added if needed. Hosts configured for plain HTTP keep being served as they are.
+pub fn add_redirects(
+    servers: &mut HashMap<u16, Server>,
+    redirects: Vec<(String, u16)>,
+    acme: &Arc<AcmeState>,
+) {
+    for (name, port) in redirects {
+        let plain = servers
+            .entry(HTTP_PORT)
+            .or_insert_with(|| Server::new(HTTP_PORT, acme.clone()));
+        let configured = plain
+            .hosts
+            .keys()
+            .any(|key| host_name(key).eq_ignore_ascii_case(&name));
+        if !plain.is_tls() && !configured {
+            debug!("Redirect: http://{} -> https port {}", name, port);
+            plain
+                .hosts
+                .insert(name, vec![Directive::HttpsRedirect { port }]);
+        }
+    }
+}
+
+/// Sends a request for `host` to the same path and query on its HTTPS `port`. ACME challenge
+/// paths aren't redirected (`None`): certificates may be validated over plain HTTP, by other
+/// ACME clients too.
+pub fn response(host: &str, port: u16, uri: &Uri) -> Option<Response<Vec<u8>>> {
+    if uri.path().starts_with(HTTP_CHALLENGE_PATH) {
+        return None;
+    }
+    let name = host_name(host);
+    if name.is_empty() {
+        return Some(error_response(StatusCode::BAD_REQUEST));
+    }
+    let authority = match port {
+        HTTPS_PORT => name.to_string(),
+        port => format!("{}:{}", name, port),
+    };
+    // `OPTIONS *` is about the server, its home page is the closest thing to send it to
+    let target = match uri.path_and_query().map(|p| p.as_str()) {
+        Some("*") | None => "/",
+        Some(target) => target,
+    };
+    let response = Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(LOCATION, format!("https://{}{}", authority, target))
+        .body(Vec::new())
+        .ok()?;
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Directive;
+    use crate::https_redirect::{add_redirects, response, HTTP_PORT};
+    use crate::Server;
+    use http::{StatusCode, Uri};
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    #[test]
+    fn test_response() -> Result<(), Box<dyn Error>> {
+        let location = |host: &str, port: u16, uri: &str| -> Result<String, Box<dyn Error>> {
+            let response = response(host, port, &uri.parse::<Uri>()?).unwrap();
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+            Ok(response.headers()["location"].to_str()?.to_string())
+        };
+        assert_eq!(
+            location("example.com", 443, "/a/b?q=1&r=2")?,
+            "https://example.com/a/b?q=1&r=2"
+        );
+        assert_eq!(
+            location("example.com:80", 8443, "/")?,
+            "https://example.com:8443/"
+        );
+        assert_eq!(
+            location("example.com", 443, "http://example.com/x?y")?,
+            "https://example.com/x?y"
+        );
+        assert_eq!(location("example.com", 443, "*")?, "https://example.com/");
+
+        let challenge = "/.well-known/acme-challenge/tok3n".parse::<Uri>()?;
+        assert!(response("example.com", 443, &challenge).is_none());
+        let no_host = response("", 443, &"/".parse::<Uri>()?).unwrap();
+        assert_eq!(no_host.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_redirects() -> Result<(), Box<dyn Error>> {
+        let acme = Default::default();
+        let mut servers = HashMap::new();
+        add_redirects(&mut servers, vec![("a.test".to_string(), 443)], &acme);
+        assert!(matches!(
+            servers[&HTTP_PORT].hosts["a.test"][..],
+            [Directive::HttpsRedirect { port: 443 }]
+        ));
+
+        // Hosts already served on port 80 are left alone
+        let mut plain = Server::new(HTTP_PORT, Default::default());
+        plain.hosts.insert("B.test:80".to_string(), Vec::new());
+        servers.insert(HTTP_PORT, plain);
+        let redirects = vec![("b.test".to_string(), 443), ("c.test".to_string(), 8443)];
+        add_redirects(&mut servers, redirects, &acme);
+        let hosts = &servers[&HTTP_PORT].hosts;
+        assert!(hosts["B.test:80"].is_empty());
+        assert!(!hosts.contains_key("b.test"));
+        assert!(matches!(
+            hosts["c.test"][..],
+            [Directive::HttpsRedirect { port: 8443 }]
+        ));
+
+        Ok(())
+    }
+}
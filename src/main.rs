@@ -1,20 +1,32 @@
+use crate::acme::{AcmeChallenge, AcmeOptions, AcmeState, ACME_TLS_ALPN};
+use crate::client_auth::{client_cert, ClientAuth, ClientAuthMode, ClientCert};
 use crate::config::{build_config, Directive};
-use crate::request::socket_to_request;
-use crate::response::{error_response, send_response};
-use http::{Response, StatusCode};
+use crate::http2::H2_ALPN;
+use crate::https_redirect::HTTP_PORT;
+use crate::mime_types::MimeTypes;
+use crate::request::{keep_alive, socket_to_request, BodyReader};
+use crate::response::{error_response, send_response, AltSvc};
+use crate::tls::{
+    crypto_provider, host_name, wildcard_matches, CertResolver, SharedConfig, TlsConfigs,
+    TlsOptions, CERT_RELOAD_INTERVAL,
+};
+use http::header::{CONNECTION, CONTENT_TYPE, HOST};
+use http::uri::Scheme;
+use http::{HeaderValue, Request, Response, StatusCode};
 use kdl::KdlDocument;
 use log::{debug, error, info};
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use std::collections::HashMap;
 use std::error::Error;
+use std::net::SocketAddr;
 use std::str;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
-use tokio_rustls::{rustls, TlsAcceptor};
+use tokio::sync::Semaphore;
+use tokio_rustls::{rustls, LazyConfigAcceptor};
 use tracing::instrument;
 use tracing::Level;
 use tracing_subscriber::fmt::format::FmtSpan;
@@ -24,17 +36,73 @@ mod config;
 mod request;
 mod response;
 
+mod acme;
+mod bridge;
+mod browse;
+mod client_auth;
+mod conditional;
+mod encode;
 mod file_server;
+mod forwarded;
+mod health;
+mod hop_by_hop;
+mod http2;
+#[cfg(feature = "http3")]
+mod http3;
+mod https_redirect;
+mod mime_types;
+mod range;
 mod reverse_proxy;
+mod rewrite;
+mod tls;
+mod tunnel;
+mod upstream;
 
 #[derive(Debug)]
 pub struct Server {
     pub port: u16,
     pub hosts: HashMap<String, Vec<Directive>>, // Host -> Directives
-    pub cert: Option<String>,
-    pub key: Option<String>,
+    pub certs: HashMap<String, (String, String)>, // Host -> (Cert, Key)
+    pub acme_hosts: HashMap<String, AcmeOptions>, // Host -> ACME settings
+    pub client_auth: HashMap<String, ClientAuth>, // Host -> Client certificate settings
+    pub tls_options: TlsOptions,
+    pub h2c: bool,   // HTTP/2 with prior knowledge on a plain listener
+    pub http3: bool, // HTTP/3 on the UDP port of a TLS listener
+    pub acme: Arc<AcmeState>,
+    pub max_connections: usize,
+    pub keep_alive_timeout: Duration,
+    pub keep_alive_requests: usize,
 }
 
+impl Server {
+    pub fn new(port: u16, acme: Arc<AcmeState>) -> Server {
+        Server {
+            port,
+            hosts: HashMap::new(),
+            certs: HashMap::new(),
+            acme_hosts: HashMap::new(),
+            client_auth: HashMap::new(),
+            tls_options: TlsOptions::default(),
+            h2c: false,
+            http3: false,
+            acme,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            keep_alive_requests: DEFAULT_KEEP_ALIVE_REQUESTS,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !self.certs.is_empty() || !self.acme_hosts.is_empty()
+    }
+}
+
+const DEFAULT_MAX_CONNECTIONS: usize = 10_000;
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(75);
+const DEFAULT_KEEP_ALIVE_REQUESTS: usize = 1000;
+/// How long clients may remember that a listener speaks HTTP/3, in seconds
+const ALT_SVC_MAX_AGE: u64 = 86400;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     info!("Cblt started");
@@ -47,49 +115,161 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let doc: KdlDocument = cbltfile_content.parse()?;
     let config = build_config(&doc)?;
 
+    for directive in config.values().flatten() {
+        if let Directive::ReverseProxy { upstreams, .. } = directive {
+            health::spawn_checks(upstreams.clone());
+        }
+    }
+
     let mut servers: HashMap<u16, Server> = HashMap::new(); // Port -> Server
+    let acme = Arc::new(AcmeState::default());
+    let mut redirects = Vec::new(); // (Host, HTTPS port)
 
     for (host, directives) in config {
+        // Plain HTTP is what hosts are served with anyway, without a `tls` directive
+        let host = match host.strip_prefix("http://") {
+            Some(host) => host.to_string(),
+            None => host,
+        };
         let mut port = 80;
-        let mut cert_path = None;
-        let mut key_path = None;
-        directives.iter().for_each(|d| {
-            if let Directive::Tls { cert, key } = d {
+        let mut cert_paths = None;
+        let mut acme_options = None;
+        let mut client_auth = None;
+        let mut tls_options = None;
+        let mut h2c = false;
+        let mut http3 = false;
+        let mut http_redirect = true;
+        let mut max_connections = None;
+        let mut keep_alive_timeout = None;
+        let mut keep_alive_requests = None;
+        directives.iter().for_each(|d| match d {
+            Directive::Tls {
+                cert,
+                key,
+                client_auth: auth,
+            } => {
+                port = 443;
+                cert_paths = Some((cert.to_string(), key.to_string()));
+                client_auth = auth.clone();
+            }
+            Directive::TlsAuto {
+                acme,
+                client_auth: auth,
+            } => {
                 port = 443;
-                cert_path = Some(cert.to_string());
-                key_path = Some(key.to_string());
+                acme_options = Some(acme.clone());
+                client_auth = auth.clone();
+            }
+            Directive::TlsOptions { options } => {
+                tls_options = Some(options.clone());
+            }
+            Directive::H2c => {
+                h2c = true;
+            }
+            Directive::Http3 => {
+                http3 = true;
             }
+            Directive::NoHttpRedirect => {
+                http_redirect = false;
+            }
+            Directive::MaxConnections { limit } => {
+                max_connections = Some(*limit);
+            }
+            Directive::KeepAlive { timeout, requests } => {
+                keep_alive_timeout = *timeout;
+                keep_alive_requests = *requests;
+            }
+            _ => {}
         });
         if host.contains(":") {
             let parts: Vec<&str> = host.split(":").collect();
             port = parts[1].parse().unwrap();
         }
         debug!("Host: {}, Port: {}", host, port);
+        if http_redirect && (cert_paths.is_some() || acme_options.is_some()) && port != HTTP_PORT {
+            redirects.push((host_name(&host).to_string(), port));
+        }
         servers
             .entry(port)
             .and_modify(|s| {
                 let hosts = &mut s.hosts;
                 hosts.insert(host.to_string(), directives.clone());
-                s.cert = cert_path.clone();
-                s.key = key_path.clone();
+                if let Some(paths) = cert_paths.clone() {
+                    s.certs.insert(host.to_string(), paths);
+                }
+                if let Some(options) = acme_options.clone() {
+                    s.acme_hosts.insert(host.to_string(), options);
+                }
+                if let Some(auth) = client_auth.clone() {
+                    s.client_auth.insert(host.to_string(), auth);
+                }
+                if let Some(options) = &tls_options {
+                    s.tls_options.merge(options);
+                }
+                s.h2c |= h2c;
+                s.http3 |= http3;
+                // Hosts sharing a listener share its limit, the strictest one wins
+                if let Some(limit) = max_connections {
+                    s.max_connections = s.max_connections.min(limit);
+                }
+                if let Some(timeout) = keep_alive_timeout {
+                    s.keep_alive_timeout = s.keep_alive_timeout.min(timeout);
+                }
+                if let Some(requests) = keep_alive_requests {
+                    s.keep_alive_requests = s.keep_alive_requests.min(requests);
+                }
             })
             .or_insert({
-                let mut hosts = HashMap::new();
-                hosts.insert(host.to_string(), directives.clone());
-                Server {
-                    port,
-                    hosts,
-                    cert: cert_path,
-                    key: key_path,
+                let mut server = Server::new(port, acme.clone());
+                server.hosts.insert(host.to_string(), directives.clone());
+                if let Some(paths) = cert_paths {
+                    server.certs.insert(host.to_string(), paths);
                 }
+                if let Some(options) = acme_options {
+                    server.acme_hosts.insert(host.to_string(), options);
+                }
+                if let Some(auth) = client_auth {
+                    server.client_auth.insert(host.to_string(), auth);
+                }
+                server.tls_options = tls_options.unwrap_or_default();
+                server.h2c = h2c;
+                server.http3 = http3;
+                server.max_connections = max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+                server.keep_alive_timeout =
+                    keep_alive_timeout.unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT);
+                server.keep_alive_requests =
+                    keep_alive_requests.unwrap_or(DEFAULT_KEEP_ALIVE_REQUESTS);
+                server
             });
     }
 
+    https_redirect::add_redirects(&mut servers, redirects, &acme);
+
+    let managed: Vec<(String, AcmeOptions)> = servers
+        .values()
+        .flat_map(|s| s.acme_hosts.iter())
+        .map(|(host, options)| (host_name(host).to_ascii_lowercase(), options.clone()))
+        .collect();
+    for (host, options) in managed {
+        // HTTP-01 challenges need a plain listener even if no host is served on it
+        if options.challenge == AcmeChallenge::Http01 {
+            servers
+                .entry(options.http_port)
+                .or_insert_with(|| Server::new(options.http_port, acme.clone()));
+        }
+        acme::spawn_renewal(host, options, acme.clone());
+    }
+
+    #[cfg(not(feature = "http3"))]
+    if servers.values().any(|s| s.http3) {
+        return Err("The 'http3' directive needs cblt built with the http3 feature".into());
+    }
+
     debug!("{:#?}", servers);
 
     for (_, server) in servers {
         tokio::spawn(async move {
-            match server_task(&server).await {
+            match server_task(Arc::new(server)).await {
                 Ok(_) => {}
                 Err(err) => {
                     error!("Error: {}", err);
@@ -103,15 +283,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn server_task(server: &Server) -> Result<(), Box<dyn Error>> {
-    let acceptor = if server.cert.is_some() {
-        let certs = CertificateDer::pem_file_iter(server.cert.clone().unwrap())?
-            .collect::<Result<Vec<_>, _>>()?;
-        let key = PrivateKeyDer::from_pem_file(server.key.clone().unwrap())?;
-        let server_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
-        Some(TlsAcceptor::from(Arc::new(server_config)))
+async fn server_task(server: Arc<Server>) -> Result<(), Box<dyn Error>> {
+    // Shared with the HTTP/3 endpoint, if any
+    let connections = Arc::new(Semaphore::new(server.max_connections));
+    let tls_config = if server.is_tls() {
+        let config: SharedConfig = Arc::new(RwLock::new(tls_config(&server)?));
+        let files: Vec<String> = server
+            .certs
+            .values()
+            .flat_map(|(cert, key)| [cert.clone(), key.clone()])
+            .chain(server.client_auth.values().map(|auth| auth.ca.clone()))
+            .collect();
+        if !files.is_empty() {
+            let reloading = server.clone();
+            tls::spawn_reload(
+                server.port,
+                files,
+                CERT_RELOAD_INTERVAL,
+                move || tls_config(&reloading),
+                config.clone(),
+            );
+        }
+        #[cfg(feature = "http3")]
+        if server.http3 {
+            let quic_server = server.clone();
+            let quic_config = config.clone();
+            let quic_connections = connections.clone();
+            tokio::spawn(async move {
+                if let Err(err) = http3::serve(quic_server, quic_config, quic_connections).await {
+                    error!("Error: {}", err);
+                }
+            });
+        }
+        Some(config)
     } else {
         None
     };
@@ -120,114 +324,347 @@ async fn server_task(server: &Server) -> Result<(), Box<dyn Error>> {
     let listener = TcpListener::bind(addr).await?;
 
     loop {
-        let (mut stream, _) = listener.accept().await?;
-        match acceptor {
-            None => {
-                directive_process(&mut stream, &server).await;
-            }
-            Some(ref acceptor) => match acceptor.accept(stream).await {
-                Ok(mut stream) => {
-                    directive_process(&mut stream, &server).await;
+        // Stop accepting while the listener is at its connection limit
+        let permit = connections.clone().acquire_owned().await?;
+        let (mut stream, peer) = listener.accept().await?;
+        let tls_config = tls_config.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            match tls_config {
+                None => {
+                    if server.h2c && http2::is_h2c(&stream, server.keep_alive_timeout).await {
+                        if let Err(err) = http2::serve(stream, peer, None, server.clone()).await {
+                            error!("Error: {}", err);
+                        }
+                    } else {
+                        directive_process(&mut stream, peer, None, &server).await;
+                    }
                 }
-                Err(err) => {
-                    error!("Error: {}", err);
+                Some(configs) => {
+                    // Handshakes started before a reload finish with the previous configs
+                    let configs = configs.read().unwrap().clone();
+                    let acceptor = LazyConfigAcceptor::new(Default::default(), stream);
+                    let handshake = match acceptor.await {
+                        Ok(start) => {
+                            let hello = start.client_hello();
+                            // ACME validators don't present client certificates
+                            let acme = hello
+                                .alpn()
+                                .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+                            let name = if acme { None } else { hello.server_name() };
+                            let (config, auth) = configs.select(name);
+                            start.into_stream(config).await.map(|stream| (stream, auth))
+                        }
+                        Err(err) => Err(err),
+                    };
+                    match handshake {
+                        // A TLS-ALPN-01 validation is over once the handshake is done
+                        Ok((stream, _))
+                            if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) => {}
+                        Ok((mut stream, auth)) => {
+                            let client =
+                                auth.and_then(|auth| client_cert(stream.get_ref().1, &auth));
+                            if stream.get_ref().1.alpn_protocol() == Some(H2_ALPN) {
+                                if let Err(err) =
+                                    http2::serve(stream, peer, client, server.clone()).await
+                                {
+                                    error!("Error: {}", err);
+                                }
+                            } else {
+                                directive_process(&mut stream, peer, client, &server).await;
+                            }
+                        }
+                        Err(err) => {
+                            error!("Error: {}", err);
+                        }
+                    }
                 }
-            },
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// The rustls configs of a TLS listener, from the certificates as they are on disk now
+fn tls_config(server: &Server) -> Result<Arc<TlsConfigs>, Box<dyn Error>> {
+    let options = &server.tls_options;
+    let provider = Arc::new(options.provider(&crypto_provider())?);
+    // Every host on the port gets its own certificate, chosen by SNI
+    let resolver = Arc::new(CertResolver::new(
+        &server.certs,
+        server.acme.clone(),
+        &provider,
+    )?);
+    let mut alpn_protocols = options.alpn_protocols();
+    if !server.acme_hosts.is_empty() {
+        alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    let build = |auth: Option<&ClientAuth>| -> Result<Arc<rustls::ServerConfig>, Box<dyn Error>> {
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(options.versions())?;
+        let builder = match auth {
+            Some(auth) => builder.with_client_cert_verifier(auth.verifier(provider.clone())?),
+            None => builder.with_no_client_auth(),
+        };
+        let mut server_config = builder.with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = alpn_protocols.clone();
+        options.apply_sessions(&mut server_config)?;
+        Ok(Arc::new(server_config))
+    };
+
+    let mut configs = TlsConfigs::new(build(None)?);
+    for (host, auth) in &server.client_auth {
+        configs.add_client_auth(host, build(Some(auth))?, auth.clone());
+    }
+    Ok(Arc::new(configs))
+}
+
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+async fn directive_process<S>(
+    socket: &mut S,
+    peer: SocketAddr,
+    client: Option<ClientCert>,
+    server: &Server,
+) where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // Survives between requests so pipelined requests aren't lost
+    let mut buf = Vec::with_capacity(8192);
+    let mut served = 0;
+
+    loop {
+        let (mut request, mut body) = match tokio::time::timeout(
+            server.keep_alive_timeout,
+            socket_to_request(socket, &mut buf),
+        )
+        .await
+        {
+            Ok(Some(request)) => request,
+            // Closed by the client, malformed or idle for too long
+            Ok(None) | Err(_) => return,
+        };
+
+        // Directives that care about the client address or the scheme find them here
+        request.extensions_mut().insert(peer);
+        if let Some(client) = &client {
+            request.extensions_mut().insert(client.clone());
+        }
+        request.extensions_mut().insert(if server.is_tls() {
+            Scheme::HTTPS
+        } else {
+            Scheme::HTTP
+        });
+        if let Some(alt_svc) = alt_svc(server, &request) {
+            request.extensions_mut().insert(alt_svc);
+        }
+
+        served += 1;
+        if served >= server.keep_alive_requests {
+            // Last request on this connection: the response will announce it
+            request
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+
+        handle_request(socket, server, &request, &mut body).await;
+
+        if !keep_alive(&request) || !body.drain(socket).await {
+            return;
         }
     }
 }
 
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
-async fn directive_process<S>(socket: &mut S, server: &Server)
-where
+async fn handle_request<S>(
+    socket: &mut S,
+    server: &Server,
+    request: &Request<Vec<u8>>,
+    body: &mut BodyReader<'_>,
+) where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    match socket_to_request(socket).await {
+    let req_opt = Some(request);
+    let host = match request.headers().get("Host") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => "",
+    };
+
+    if !server.is_tls() {
+        if let Some(key_authorization) = server.acme.http_challenge(request.uri().path()) {
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "text/plain")
+                .body(key_authorization.into_bytes())
+                .unwrap();
+            let _ = send_response(socket, response, req_opt).await;
+            return;
+        }
+    }
+
+    let host_config = match host_config(server, host) {
+        Some(cfg) => cfg,
         None => {
+            let response = error_response(StatusCode::FORBIDDEN);
+            let _ = send_response(socket, response, req_opt).await;
             return;
         }
-        Some(request) => {
-            let req_opt = Some(&request);
-            let host = match request.headers().get("Host") {
-                Some(h) => h.to_str().unwrap_or(""),
-                None => "",
-            };
-
-            // find host starting with "*"
-            let cfg_opt = server.hosts.iter().find(|(k, _)| k.starts_with("*"));
-            let host_config = match cfg_opt {
-                None => {
-                    let host_config = match server.hosts.get(host) {
-                        Some(cfg) => cfg,
-                        None => {
-                            let req_opt = Some(&request);
-                            let response = error_response(StatusCode::FORBIDDEN);
-                            let _ = send_response(socket, response, req_opt).await;
-                            return;
-                        }
-                    };
-                    host_config
+    };
+
+    if let Some(auth) = host_client_auth(host_config) {
+        let client = request.extensions().get::<ClientCert>();
+        let verified = client.is_some_and(|client| client.ca == auth.ca);
+        // The connection was set up for another host's name, so its client wasn't
+        // authenticated the way this host wants: it has to reconnect with this name
+        if !verified && (client.is_some() || auth.mode == ClientAuthMode::Require) {
+            let response = error_response(StatusCode::MISDIRECTED_REQUEST);
+            let _ = send_response(socket, response, req_opt).await;
+            return;
+        }
+    }
+
+    let mut root_path = None;
+    let mut handled = false;
+    let default_mime_types = MimeTypes::default();
+    let mime_types = host_config
+        .iter()
+        .find_map(|d| match d {
+            Directive::MimeTypes { types } => Some(types),
+            _ => None,
+        })
+        .unwrap_or(&default_mime_types);
+    let encode = host_config.iter().find_map(|d| match d {
+        Directive::Encode { encode } => Some(encode),
+        _ => None,
+    });
+
+    for directive in host_config {
+        match directive {
+            Directive::Root { pattern, path } => {
+                #[cfg(debug_assertions)]
+                debug!("Root: {} -> {}", pattern, path);
+                if matches_pattern(pattern, request.uri().path()) {
+                    root_path = Some(path.clone());
                 }
-                Some((_, cfg)) => cfg,
-            };
-
-            let mut root_path = None;
-            let mut handled = false;
-
-            for directive in host_config {
-                match directive {
-                    Directive::Root { pattern, path } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Root: {} -> {}", pattern, path);
-                        if matches_pattern(pattern, request.uri().path()) {
-                            root_path = Some(path.clone());
-                        }
-                    }
-                    Directive::FileServer => {
-                        #[cfg(debug_assertions)]
-                        debug!("File server");
-                        file_server::directive(&root_path, &request, &mut handled, socket, req_opt)
-                            .await;
-                        break;
-                    }
-                    Directive::ReverseProxy {
-                        pattern,
-                        destination,
-                    } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Reverse proxy: {} -> {}", pattern, destination);
-                        reverse_proxy::directive(
-                            &request,
-                            &mut handled,
-                            socket,
-                            req_opt,
-                            pattern,
-                            destination,
-                        )
-                        .await;
-                        break;
-                    }
-                    Directive::Redir { destination } => {
-                        let dest = destination.replace("{uri}", request.uri().path());
-                        let response = Response::builder()
-                            .status(StatusCode::FOUND)
-                            .header("Location", &dest)
-                            .body(Vec::new()) // Empty body for redirects
-                            .unwrap();
-                        let _ = send_response(socket, response, req_opt).await;
-                        handled = true;
-                        break;
-                    }
-                    Directive::Tls { .. } => {}
+            }
+            Directive::FileServer { options } => {
+                #[cfg(debug_assertions)]
+                debug!("File server");
+                file_server::directive(
+                    &root_path,
+                    options,
+                    mime_types,
+                    encode,
+                    request,
+                    &mut handled,
+                    socket,
+                    req_opt,
+                )
+                .await;
+                break;
+            }
+            Directive::ReverseProxy { pattern, upstreams } => {
+                #[cfg(debug_assertions)]
+                debug!(
+                    "Reverse proxy: {} -> {} upstreams",
+                    pattern,
+                    upstreams.list.len()
+                );
+                reverse_proxy::directive(
+                    request,
+                    body,
+                    &mut handled,
+                    socket,
+                    req_opt,
+                    pattern,
+                    upstreams,
+                    encode,
+                )
+                .await;
+                if handled {
+                    break;
                 }
             }
-
-            if !handled {
-                let response = error_response(StatusCode::NOT_FOUND);
+            Directive::Redir { destination } => {
+                let dest = destination.replace("{uri}", request.uri().path());
+                let response = Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", &dest)
+                    .body(Vec::new()) // Empty body for redirects
+                    .unwrap();
                 let _ = send_response(socket, response, req_opt).await;
+                handled = true;
+                break;
+            }
+            Directive::HttpsRedirect { port } => {
+                if let Some(response) = https_redirect::response(host, *port, request.uri()) {
+                    let _ = send_response(socket, response, req_opt).await;
+                    handled = true;
+                }
+                break;
             }
+            Directive::Tls { .. }
+            | Directive::TlsAuto { .. }
+            | Directive::TlsOptions { .. }
+            | Directive::H2c
+            | Directive::Http3
+            | Directive::NoHttpRedirect
+            | Directive::MaxConnections { .. }
+            | Directive::KeepAlive { .. }
+            | Directive::MimeTypes { .. }
+            | Directive::Encode { .. } => {}
+        }
+    }
+
+    if !handled {
+        let response = error_response(StatusCode::NOT_FOUND);
+        let _ = send_response(socket, response, req_opt).await;
+    }
+}
+
+/// Client certificate settings of a host, from its `tls` directive
+fn host_client_auth(directives: &[Directive]) -> Option<&ClientAuth> {
+    directives.iter().find_map(|d| match d {
+        Directive::Tls { client_auth, .. } | Directive::TlsAuto { client_auth, .. } => {
+            client_auth.as_ref()
+        }
+        _ => None,
+    })
+}
+
+/// Announces the listener's HTTP/3 endpoint to clients of hosts that can be served on it:
+/// those authenticating clients aren't, as QUIC handshakes don't ask for certificates
+fn alt_svc(server: &Server, request: &Request<Vec<u8>>) -> Option<AltSvc> {
+    if !server.http3 || !server.is_tls() {
+        return None;
+    }
+    let host = request.headers().get(HOST)?.to_str().ok()?;
+    if host_config(server, host).is_some_and(|config| host_client_auth(config).is_some()) {
+        return None;
+    }
+    let value = format!("h3=\":{}\"; ma={}", server.port, ALT_SVC_MAX_AGE);
+    HeaderValue::from_str(&value).ok().map(AltSvc)
+}
+
+/// Directives of the host a request is for: an exact match, then a `*.example.com`
+/// wildcard, then the `*` catch-all
+fn host_config<'a>(server: &'a Server, host: &str) -> Option<&'a Vec<Directive>> {
+    if let Some(cfg) = server.hosts.get(host) {
+        return Some(cfg);
+    }
+    let name = host_name(host);
+    let mut wildcard = None;
+    let mut catch_all = None;
+    for (key, cfg) in &server.hosts {
+        let key = host_name(key);
+        if key.eq_ignore_ascii_case(name) {
+            return Some(cfg);
+        } else if key == "*" {
+            catch_all = Some(cfg);
+        } else if wildcard_matches(key, name) {
+            wildcard = Some(cfg);
         }
     }
+    wildcard.or(catch_all)
 }
 
 #[allow(dead_code)]
@@ -251,8 +688,7 @@ fn only_in_production() {
 fn matches_pattern(pattern: &str, path: &str) -> bool {
     if pattern == "*" {
         true
-    } else if pattern.ends_with("*") {
-        let prefix = &pattern[..pattern.len() - 1];
+    } else if let Some(prefix) = pattern.strip_suffix("*") {
         path.starts_with(prefix)
     } else {
         pattern == path
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Extension to MIME type mapping for served files: the built-in table plus the
+/// per-host additions and overrides from the `mime_types` block.
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    overrides: HashMap<String, String>, // Lowercase extension without dot -> MIME type
+    default: Option<String>,
+}
+
+impl MimeTypes {
+    pub fn new(overrides: HashMap<String, String>, default: Option<String>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(ext, mime)| (ext.trim_start_matches('.').to_ascii_lowercase(), mime))
+            .collect();
+        MimeTypes { overrides, default }
+    }
+
+    pub fn lookup(&self, path: &Path) -> &str {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        if let Some(ext) = ext {
+            if let Some(mime) = self.overrides.get(&ext) {
+                return mime;
+            }
+            if let Some(mime) = builtin_mime_type(&ext) {
+                return mime;
+            }
+        }
+        self.default.as_deref().unwrap_or(DEFAULT_MIME_TYPE)
+    }
+}
+
+fn builtin_mime_type(ext: &str) -> Option<&'static str> {
+    let mime = match ext {
+        // Text
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "ics" => "text/calendar; charset=utf-8",
+        "json" | "map" => "application/json; charset=utf-8",
+        "jsonld" => "application/ld+json; charset=utf-8",
+        "webmanifest" => "application/manifest+json; charset=utf-8",
+        "xml" => "application/xml; charset=utf-8",
+        "rss" => "application/rss+xml; charset=utf-8",
+        "atom" => "application/atom+xml; charset=utf-8",
+        "yaml" | "yml" => "application/yaml; charset=utf-8",
+        "svg" => "image/svg+xml; charset=utf-8",
+        // Images
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        // Fonts
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "eot" => "application/vnd.ms-fontobject",
+        // Audio and video
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        "mov" => "video/quicktime",
+        // Applications and archives
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "7z" => "application/x-7z-compressed",
+        "br" => "application/x-brotli",
+        "zst" => "application/zstd",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mime_types::MimeTypes;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::path::Path;
+
+    #[test]
+    fn test_lookup() -> Result<(), Box<dyn Error>> {
+        let mime_types = MimeTypes::default();
+        assert_eq!(
+            mime_types.lookup(Path::new("index.html")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            mime_types.lookup(Path::new("app.JS")),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            mime_types.lookup(Path::new("pkg_bg.wasm")),
+            "application/wasm"
+        );
+        assert_eq!(
+            mime_types.lookup(Path::new("README")),
+            "application/octet-stream"
+        );
+
+        let overrides = HashMap::from([
+            (".wasm".to_string(), "application/x-wasm".to_string()),
+            ("Log".to_string(), "text/plain; charset=utf-8".to_string()),
+        ]);
+        let mime_types = MimeTypes::new(overrides, Some("text/plain".to_string()));
+        assert_eq!(mime_types.lookup(Path::new("a.wasm")), "application/x-wasm");
+        assert_eq!(
+            mime_types.lookup(Path::new("server.log")),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(mime_types.lookup(Path::new("a.png")), "image/png");
+        assert_eq!(mime_types.lookup(Path::new("LICENSE")), "text/plain");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// More ranges than this in a single request are ignored and the whole file is sent
+const MAX_RANGES: usize = 32;
+
+/// Outcome of evaluating a `Range` header against a representation of `size` bytes.
+#[derive(Debug, PartialEq)]
+pub enum ByteRanges {
+    /// No usable `Range` header: send the whole representation
+    Full,
+    /// Inclusive `(first, last)` byte positions, sorted and without overlaps
+    Partial(Vec<(u64, u64)>),
+    /// None of the requested ranges overlap the representation: 416
+    Unsatisfiable,
+}
+
+/// Parses a `Range: bytes=...` header (RFC 9110, section 14.1.2).
+///
+/// Syntactically invalid headers and other range units are ignored, as the RFC allows.
+pub fn parse_range(header: &str, size: u64) -> ByteRanges {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return ByteRanges::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return ByteRanges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return ByteRanges::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes
+            let Ok(suffix) = last.parse::<u64>() else {
+                return ByteRanges::Full;
+            };
+            if suffix == 0 || size == 0 {
+                None
+            } else {
+                Some((size.saturating_sub(suffix), size - 1))
+            }
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return ByteRanges::Full;
+            };
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(last) if last >= first => last,
+                    _ => return ByteRanges::Full,
+                }
+            };
+            if first >= size {
+                None
+            } else {
+                Some((first, last.min(size - 1)))
+            }
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+        if ranges.len() > MAX_RANGES {
+            return ByteRanges::Full;
+        }
+    }
+
+    if ranges.is_empty() {
+        return ByteRanges::Unsatisfiable;
+    }
+
+    // Coalesce overlapping and adjacent ranges so nothing is sent twice
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    ByteRanges::Partial(merged)
+}
+
+/// Reads a sequence of readers one after another, used for `multipart/byteranges` bodies.
+pub struct ConcatReader {
+    parts: Vec<Pin<Box<dyn AsyncRead + Send>>>, // Reversed: the next part is popped from the end
+}
+
+impl ConcatReader {
+    pub fn new(mut parts: Vec<Pin<Box<dyn AsyncRead + Send>>>) -> Self {
+        parts.reverse();
+        ConcatReader { parts }
+    }
+}
+
+impl fmt::Debug for ConcatReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConcatReader")
+            .field("parts", &self.parts.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for ConcatReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while let Some(part) = self.parts.last_mut() {
+            let filled = buf.filled().len();
+            match part.as_mut().poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == filled => {
+                    // This part is exhausted, move on to the next one
+                    self.parts.pop();
+                }
+                other => return other,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::range::{parse_range, ByteRanges};
+    use std::error::Error;
+
+    #[test]
+    fn test_parse_range() -> Result<(), Box<dyn Error>> {
+        let size = 1000;
+        let cases = [
+            ("bytes=0-499", ByteRanges::Partial(vec![(0, 499)])),
+            ("bytes=500-", ByteRanges::Partial(vec![(500, 999)])),
+            ("bytes=-200", ByteRanges::Partial(vec![(800, 999)])),
+            ("bytes=-5000", ByteRanges::Partial(vec![(0, 999)])),
+            ("bytes=900-5000", ByteRanges::Partial(vec![(900, 999)])),
+            (
+                "Bytes = 0-0, -1",
+                ByteRanges::Partial(vec![(0, 0), (999, 999)]),
+            ),
+            (
+                "bytes=0-99,200-299",
+                ByteRanges::Partial(vec![(0, 99), (200, 299)]),
+            ),
+            // Overlapping and adjacent ranges are merged
+            (
+                "bytes=0-99,50-150,151-160",
+                ByteRanges::Partial(vec![(0, 160)]),
+            ),
+            ("bytes=1000-", ByteRanges::Unsatisfiable),
+            ("bytes=-0", ByteRanges::Unsatisfiable),
+            ("bytes=1000-1100,2000-", ByteRanges::Unsatisfiable),
+            // Unsatisfiable ranges are dropped when others are fine
+            ("bytes=0-9,5000-", ByteRanges::Partial(vec![(0, 9)])),
+            ("bytes=500-100", ByteRanges::Full),
+            ("bytes=abc", ByteRanges::Full),
+            ("bytes=1-x", ByteRanges::Full),
+            ("items=0-10", ByteRanges::Full),
+            ("0-10", ByteRanges::Full),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(parse_range(header, size), expected, "{}", header);
+        }
+
+        assert_eq!(parse_range("bytes=0-", 0), ByteRanges::Unsatisfiable);
+        let many = format!("bytes={}", vec!["0-0"; 100].join(","));
+        assert_eq!(parse_range(&many, size), ByteRanges::Full);
+
+        Ok(())
+    }
+}
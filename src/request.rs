@@ -1,90 +1,322 @@
 use crate::response::{error_response, send_response};
+use bytes::Bytes;
+use http::header::{CONNECTION, EXPECT};
 use http::Version;
-use http::{Request, StatusCode};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
 use httparse::Status;
-use std::str;
 use log::debug;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use std::str;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::instrument;
 
+/// Reads the head of the next request from the connection.
+///
+/// `buf` belongs to the connection and outlives a single request: bytes read past the
+/// request head are left in it. The body isn't read here, the returned request carries an
+/// empty one and the `BodyReader` streams the actual body from the connection on demand.
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
-pub async fn socket_to_request<S>(socket: &mut S) -> Option<Request<Vec<u8>>>
+pub async fn socket_to_request<'a, S>(
+    socket: &mut S,
+    buf: &'a mut Vec<u8>,
+) -> Option<(Request<Vec<u8>>, BodyReader<'a>)>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    let mut buf = Vec::with_capacity(8192);
-    let mut reader = BufReader::new(&mut *socket);
-
     // Read data from the socket until we can parse the headers
     loop {
-        let mut temp_buf = [0; 1024];
-        let bytes_read = reader.read(&mut temp_buf).await.unwrap_or(0);
-        if bytes_read == 0 {
-            break; // Connection closed
-        }
-        buf.extend_from_slice(&temp_buf[..bytes_read]);
-
-        // Try to parse the headers
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut req = httparse::Request::new(&mut headers);
-
-        match req.parse(&buf) {
-            Ok(Status::Complete(header_len)) => {
-                // Headers parsed successfully
-                let req_str = match str::from_utf8(&buf[..header_len]) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        let response = error_response(StatusCode::BAD_REQUEST);
-                        let _ = send_response(socket, response, None).await;
-                        return None;
-                    }
-                };
-
-                // Parse the request headers and get Content-Length
-                let (mut request, content_length) = match parse_request_headers(req_str) {
-                    Some((req, content_length)) => (req, content_length),
-                    None => {
-                        let response = error_response(StatusCode::BAD_REQUEST);
-                        let _ = send_response(socket, response, None).await;
-                        return None;
-                    }
-                };
+        if !buf.is_empty() {
+            // Try to parse the headers
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut req = httparse::Request::new(&mut headers);
 
-                let mut body = buf[header_len..].to_vec(); // Any remaining data is part of body
+            match req.parse(buf) {
+                Ok(Status::Complete(header_len)) => {
+                    // Headers parsed successfully
+                    let req_str = match str::from_utf8(&buf[..header_len]) {
+                        Ok(v) => v,
+                        Err(_) => {
+                            let response = error_response(StatusCode::BAD_REQUEST);
+                            let _ = send_response(socket, response, None).await;
+                            return None;
+                        }
+                    };
 
-                if let Some(content_length) = content_length {
-                    while body.len() < content_length {
-                        let mut temp_buf = vec![0; content_length - body.len()];
-                        let bytes_read = reader.read(&mut temp_buf).await.unwrap_or(0);
-                        if bytes_read == 0 {
-                            break; // Connection closed
+                    // Parse the request headers and find where the body ends
+                    let (request, framing) = match parse_request_headers(req_str) {
+                        Some((req, framing)) => (req, framing),
+                        None => {
+                            let response = error_response(StatusCode::BAD_REQUEST);
+                            let _ = send_response(socket, response, None).await;
+                            return None;
                         }
-                        body.extend_from_slice(&temp_buf[..bytes_read]);
-                    }
-                }
+                    };
 
-                *request.body_mut() = body;
-                #[cfg(debug_assertions)]
-                debug!("{:?}", request);
-                return Some(request);
+                    // Only the body and whatever follows it stay in the buffer
+                    buf.drain(..header_len);
+                    let state = match framing {
+                        Framing::Length(0) => BodyState::Done,
+                        Framing::Length(length) => BodyState::Length(length),
+                        Framing::Chunked => BodyState::ChunkSize,
+                    };
+                    let expect_continue = !matches!(state, BodyState::Done)
+                        && request
+                            .headers()
+                            .get(EXPECT)
+                            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+
+                    #[cfg(debug_assertions)]
+                    debug!("{:?}", request);
+                    let body = BodyReader {
+                        buf,
+                        state,
+                        trailers: HeaderMap::new(),
+                        expect_continue,
+                        closed: false,
+                    };
+                    return Some((request, body));
+                }
+                Ok(Status::Partial) => {
+                    // Need to read more data
+                }
+                Err(_) => {
+                    let response = error_response(StatusCode::BAD_REQUEST);
+                    let _ = send_response(socket, response, None).await;
+                    return None;
+                }
             }
-            Ok(Status::Partial) => {
-                // Need to read more data
-                continue;
+        }
+
+        if read_more(socket, buf).await == 0 {
+            return None; // Connection closed
+        }
+    }
+}
+
+/// Bodies left unread by the directive are skipped to reuse the connection,
+/// unless they are bigger than this, then the connection is closed instead
+const MAX_DRAIN: u64 = 1024 * 1024;
+/// Longest chunk size line, extensions included
+const MAX_CHUNK_LINE: usize = 4096;
+const MAX_TRAILERS: usize = 32;
+const MAX_TRAILERS_SIZE: usize = 8192;
+
+/// How the body of a request is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+/// Where a `BodyReader` is in the body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyState {
+    Length(u64),    // Left of a body with a Content-Length
+    ChunkSize,      // Size line of the next chunk
+    ChunkData(u64), // Left of the current chunk
+    ChunkEnd,       // CRLF closing a chunk
+    Trailers,       // Fields after the last chunk, up to an empty line
+    Done,
+}
+
+/// The body of the current request, still waiting in the connection.
+/// Chunked bodies come out decoded, their trailers are kept once read.
+#[derive(Debug)]
+pub struct BodyReader<'a> {
+    buf: &'a mut Vec<u8>, // Connection buffer, may already hold the start of the body
+    state: BodyState,
+    trailers: HeaderMap,
+    expect_continue: bool, // The client waits for "100 Continue" before sending the body
+    closed: bool,          // No more requests on the connection
+}
+
+impl BodyReader<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.state == BodyState::Done
+    }
+
+    /// Trailer fields of a chunked body, empty until all of it has been read
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    /// Reads the next piece of the body, `None` once all of it has been read.
+    /// Fails with `InvalidData` on a malformed chunked body.
+    pub async fn read_chunk<S>(&mut self, socket: &mut S) -> std::io::Result<Option<Bytes>>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        if self.state == BodyState::Done {
+            return Ok(None);
+        }
+        if self.expect_continue {
+            self.expect_continue = false;
+            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+            socket.flush().await?;
+        }
+        loop {
+            match self.state {
+                BodyState::Length(remaining) | BodyState::ChunkData(remaining) => {
+                    if self.buf.is_empty() {
+                        self.fill(socket).await?;
+                    }
+                    let len = (self.buf.len() as u64).min(remaining);
+                    let left = remaining - len;
+                    self.state = match self.state {
+                        BodyState::Length(_) if left == 0 => BodyState::Done,
+                        BodyState::Length(_) => BodyState::Length(left),
+                        _ if left == 0 => BodyState::ChunkEnd,
+                        _ => BodyState::ChunkData(left),
+                    };
+                    return Ok(Some(Bytes::from(
+                        self.buf.drain(..len as usize).collect::<Vec<u8>>(),
+                    )));
+                }
+                BodyState::ChunkSize => match httparse::parse_chunk_size(self.buf) {
+                    // httparse reads an empty size as 0
+                    Ok(Status::Complete((len, size))) if self.buf[0].is_ascii_hexdigit() => {
+                        self.buf.drain(..len);
+                        self.state = if size == 0 {
+                            BodyState::Trailers
+                        } else {
+                            BodyState::ChunkData(size)
+                        };
+                    }
+                    Ok(Status::Partial) if self.buf.len() < MAX_CHUNK_LINE => {
+                        self.fill(socket).await?
+                    }
+                    _ => return Err(std::io::ErrorKind::InvalidData.into()),
+                },
+                BodyState::ChunkEnd => {
+                    if self.buf.len() < 2 {
+                        self.fill(socket).await?;
+                        continue;
+                    }
+                    if self.buf[..2] != *b"\r\n" {
+                        return Err(std::io::ErrorKind::InvalidData.into());
+                    }
+                    self.buf.drain(..2);
+                    self.state = BodyState::ChunkSize;
+                }
+                BodyState::Trailers => {
+                    let mut headers = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+                    match httparse::parse_headers(self.buf, &mut headers) {
+                        Ok(Status::Complete((len, fields))) => {
+                            for field in fields {
+                                let name = HeaderName::from_bytes(field.name.as_bytes());
+                                let value = HeaderValue::from_bytes(field.value);
+                                if let (Ok(name), Ok(value)) = (name, value) {
+                                    self.trailers.append(name, value);
+                                }
+                            }
+                            self.buf.drain(..len);
+                            self.state = BodyState::Done;
+                        }
+                        Ok(Status::Partial) if self.buf.len() < MAX_TRAILERS_SIZE => {
+                            self.fill(socket).await?
+                        }
+                        _ => return Err(std::io::ErrorKind::InvalidData.into()),
+                    }
+                }
+                BodyState::Done => return Ok(None),
             }
-            Err(_) => {
-                let response = error_response(StatusCode::BAD_REQUEST);
-                let _ = send_response(socket, response, None).await;
-                return None;
+        }
+    }
+
+    /// Reads more of the body into the buffer
+    async fn fill<S>(&mut self, socket: &mut S) -> std::io::Result<()>
+    where
+        S: AsyncReadExt + Unpin,
+    {
+        if read_more(socket, self.buf).await == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Hands the connection over to another protocol after a `101 Switching Protocols`:
+    /// returns what the client already sent past the request, the connection won't
+    /// carry HTTP requests anymore.
+    pub fn take_upgraded(&mut self) -> Vec<u8> {
+        self.closed = true;
+        self.state = BodyState::Done;
+        std::mem::take(self.buf)
+    }
+
+    /// Keeps the connection from carrying more requests, e.g. after a response broke off
+    /// once its head was sent: the client can only tell it is incomplete when it closes.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Skips what is left of the body so the next request can be read.
+    /// Returns false when the connection can't be reused.
+    pub async fn drain<S>(&mut self, socket: &mut S) -> bool
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        if self.closed {
+            return false;
+        }
+        if self.state == BodyState::Done {
+            return true;
+        }
+        // A client still waiting for "100 Continue" may never send the body
+        if self.expect_continue
+            || matches!(self.state, BodyState::Length(remaining) if remaining > MAX_DRAIN)
+        {
+            return false;
+        }
+        // Chunked bodies only tell their size as they go
+        let mut drained = 0;
+        loop {
+            match self.read_chunk(socket).await {
+                Ok(Some(chunk)) => {
+                    drained += chunk.len() as u64;
+                    if drained > MAX_DRAIN {
+                        return false;
+                    }
+                }
+                Ok(None) => return true,
+                Err(_) => return false,
             }
         }
     }
+}
 
-    None
+async fn read_more<S>(socket: &mut S, buf: &mut Vec<u8>) -> usize
+where
+    S: AsyncReadExt + Unpin,
+{
+    let mut temp_buf = [0; 8192];
+    let bytes_read = socket.read(&mut temp_buf).await.unwrap_or(0);
+    buf.extend_from_slice(&temp_buf[..bytes_read]);
+    bytes_read
+}
+
+/// Whether the connection may stay open after responding to `request`:
+/// HTTP/1.1 defaults to persistent connections, HTTP/1.0 only with `Connection: keep-alive`.
+pub fn keep_alive<B>(request: &Request<B>) -> bool {
+    let mut close = false;
+    let mut keep_alive = false;
+    for value in request.headers().get_all(CONNECTION) {
+        for token in value.to_str().unwrap_or("").split(',') {
+            let token = token.trim();
+            if token.eq_ignore_ascii_case("close") {
+                close = true;
+            } else if token.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            }
+        }
+    }
+    match request.version() {
+        Version::HTTP_11 => !close,
+        Version::HTTP_10 => keep_alive && !close,
+        _ => false,
+    }
 }
 
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
-pub fn parse_request_headers(req_str: &str) -> Option<(Request<Vec<u8>>, Option<usize>)> {
+pub fn parse_request_headers(req_str: &str) -> Option<(Request<Vec<u8>>, Framing)> {
     let mut headers = [httparse::EMPTY_HEADER; 32];
     let mut req = httparse::Request::new(&mut headers);
 
@@ -101,6 +333,7 @@ pub fn parse_request_headers(req_str: &str) -> Option<(Request<Vec<u8>>, Option<
             let mut builder = Request::builder().method(method).uri(path).version(version);
 
             let mut content_length = None;
+            let mut chunked = false;
 
             for header in req.headers.iter() {
                 let name = header.name;
@@ -108,18 +341,36 @@ pub fn parse_request_headers(req_str: &str) -> Option<(Request<Vec<u8>>, Option<
                 builder = builder.header(name, value);
 
                 if name.eq_ignore_ascii_case("Content-Length") {
-                    if let Ok(s) = std::str::from_utf8(value) {
-                        if let Ok(len) = s.trim().parse::<usize>() {
-                            content_length = Some(len);
-                        }
+                    // Digits only, `parse` would take a sign other servers may not
+                    let value = value.trim_ascii();
+                    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+                        return None;
+                    }
+                    let len = str::from_utf8(value).ok()?.parse::<u64>().ok()?;
+                    // Repeated fields must agree
+                    if content_length.is_some_and(|l| l != len) {
+                        return None;
                     }
+                    content_length = Some(len);
+                } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                    // Other codings would reach directives and upstreams still applied,
+                    // with nothing left to say so once the header is dropped
+                    let coding = str::from_utf8(value).ok()?.trim();
+                    if chunked || !coding.eq_ignore_ascii_case("chunked") {
+                        return None;
+                    }
+                    chunked = true;
                 }
             }
 
-            builder
-                .body(Vec::new())
-                .ok()
-                .map(|req| (req, content_length))
+            let framing = match (chunked, content_length) {
+                // Servers disagreeing on which one wins is what request smuggling exploits
+                (true, Some(_)) => return None,
+                (true, None) => Framing::Chunked,
+                (false, length) => Framing::Length(length.unwrap_or(0)),
+            };
+
+            builder.body(Vec::new()).ok().map(|req| (req, framing))
         }
         Ok(Status::Partial) => None, // Incomplete request
         Err(_) => None,              // Parsing failed
@@ -129,8 +380,9 @@ pub fn parse_request_headers(req_str: &str) -> Option<(Request<Vec<u8>>, Option<
 #[cfg(test)]
 mod tests {
     use crate::only_in_debug;
-    use crate::request::{parse_request_headers};
+    use crate::request::{keep_alive, parse_request_headers, socket_to_request, Framing};
     use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     fn test_simple() -> Result<(), Box<dyn Error>> {
@@ -149,4 +401,225 @@ Content-Length: 15\r\n\r\n\
 
         Ok(())
     }
+
+    #[test]
+    fn test_keep_alive() -> Result<(), Box<dyn Error>> {
+        let cases = [
+            ("GET / HTTP/1.1\r\nHost: a\r\n\r\n", true),
+            ("GET / HTTP/1.1\r\nConnection: close\r\n\r\n", false),
+            (
+                "GET / HTTP/1.1\r\nConnection: Upgrade, Close\r\n\r\n",
+                false,
+            ),
+            ("GET / HTTP/1.0\r\nHost: a\r\n\r\n", false),
+            ("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+        ];
+        for (request_str, expected) in cases {
+            let (req, _) = parse_request_headers(request_str).unwrap();
+            assert_eq!(keep_alive(&req), expected, "{}", request_str);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pipelined() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n")
+            .await?;
+        drop(client);
+
+        let mut buf = Vec::new();
+        let (first, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(first.uri().path(), "/a");
+        assert_eq!(
+            body.read_chunk(&mut server).await?.as_deref(),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(body.read_chunk(&mut server).await?, None);
+        let (second, body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(second.uri().path(), "/b");
+        assert!(body.is_empty());
+        assert!(socket_to_request(&mut server, &mut buf).await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_body_streaming() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let body = vec![b'x'; 10_000];
+        let sent = body.clone();
+        let writer = tokio::spawn(async move {
+            client
+                .write_all(b"PUT /upload HTTP/1.1\r\nContent-Length: 10000\r\n\r\n")
+                .await?;
+            client.write_all(&sent).await?;
+            client.write_all(b"GET /next HTTP/1.1\r\n\r\n").await?;
+            Ok::<_, std::io::Error>(client)
+        });
+
+        let mut buf = Vec::new();
+        let (request, mut reader) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.uri().path(), "/upload");
+        let mut received = Vec::new();
+        while let Some(chunk) = reader.read_chunk(&mut server).await? {
+            // The duplex buffer is small, the body can only arrive in pieces
+            assert!(chunk.len() <= 8192);
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, body);
+        let _client = writer.await??;
+
+        let (request, _) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.uri().path(), "/next");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_framing() -> Result<(), Box<dyn Error>> {
+        let cases = [
+            ("GET / HTTP/1.1\r\n\r\n", Some(Framing::Length(0))),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n",
+                Some(Framing::Length(5)),
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n",
+                Some(Framing::Chunked),
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                None,
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+                None,
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+                Some(Framing::Length(5)),
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+                None,
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+                None,
+            ),
+            ("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", None),
+            ("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n", None),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", None),
+        ];
+        for (request_str, expected) in cases {
+            let framing = parse_request_headers(request_str).map(|(_, framing)| framing);
+            assert_eq!(framing, expected, "{}", request_str);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunked() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+5;name=value\r\nhello\r\n\
+7\r\n, world\r\n\
+0\r\nChecksum: abc\r\n\r\n\
+GET /b HTTP/1.1\r\n\r\n",
+            )
+            .await?;
+
+        let mut buf = Vec::new();
+        let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert!(!body.is_empty());
+        let mut received = Vec::new();
+        while let Some(chunk) = body.read_chunk(&mut server).await? {
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, b"hello, world");
+        assert_eq!(body.trailers()["checksum"], "abc");
+        let (request, _) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.uri().path(), "/b");
+
+        // Bodies left unread are skipped chunk by chunk
+        client
+            .write_all(b"PUT /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nGET /d HTTP/1.1\r\n\r\n")
+            .await?;
+        let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert!(body.drain(&mut server).await);
+        let (request, _) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.uri().path(), "/d");
+
+        // Malformed chunks fail the body
+        for malformed in [&b"x\r\n"[..], b"\r\n", b"3\r\nabcd\r\n"] {
+            client
+                .write_all(b"PUT /e HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await?;
+            client.write_all(malformed).await?;
+            let mut buf = Vec::new();
+            let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+            let mut result = body.read_chunk(&mut server).await;
+            while let Ok(Some(_)) = result {
+                result = body.read_chunk(&mut server).await;
+            }
+            assert_eq!(
+                result.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{:?}",
+                malformed
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_smuggling() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /admin HTTP/1.1\r\n\r\n")
+            .await?;
+        let mut buf = Vec::new();
+        assert!(socket_to_request(&mut server, &mut buf).await.is_none());
+        drop(server);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\n\r\n")
+            .await?;
+        let mut buf = Vec::new();
+        let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert!(body.drain(&mut server).await);
+        let (request, _) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert_eq!(request.uri().path(), "/b");
+
+        // Closed connections aren't reused, even with the whole body read
+        client.write_all(b"GET /x HTTP/1.1\r\n\r\n").await?;
+        let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        body.close();
+        assert!(!body.drain(&mut server).await);
+
+        // Nothing is drained while the client waits for 100 Continue
+        client
+            .write_all(b"PUT /c HTTP/1.1\r\nContent-Length: 3\r\nExpect: 100-continue\r\n\r\n")
+            .await?;
+        let (_, mut body) = socket_to_request(&mut server, &mut buf).await.unwrap();
+        assert!(!body.drain(&mut server).await);
+
+        Ok(())
+    }
 }
@@ -1,14 +1,19 @@
-use http::{Request, Response, StatusCode};
+use crate::request::keep_alive;
+use http::header::{ALT_SVC, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
 use log::{debug, info};
 use std::error::Error;
-use std::fmt::Debug;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::instrument;
 
+/// `Alt-Svc` announcing another protocol the listener speaks, found in the request extensions
+#[derive(Debug, Clone)]
+pub struct AltSvc(pub HeaderValue);
+
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
 pub async fn send_response_file<S>(
     socket: &mut S,
-    response: Response<impl AsyncReadExt + Unpin + Debug>,
+    response: Response<impl AsyncReadExt + Unpin>,
     req_opt: Option<&Request<Vec<u8>>>,
 ) -> Result<(), Box<dyn Error>>
 where
@@ -36,7 +41,19 @@ where
     } else {
         info!("Response: {}", response.status().as_u16());
     }
-    let (parts, mut body) = response.into_parts();
+    let (mut parts, mut body) = response.into_parts();
+    set_connection_header(&mut parts.headers, req_opt);
+    if let Some(AltSvc(alt_svc)) = req_opt.and_then(|req| req.extensions().get::<AltSvc>()) {
+        parts.headers.insert(ALT_SVC, alt_svc.clone());
+    }
+    // Bodies of unknown length (e.g. compressed on the fly) are sent chunked,
+    // so such responses must only be produced for HTTP/1.1 requests
+    let chunked = !parts.headers.contains_key(CONTENT_LENGTH);
+    if chunked {
+        parts
+            .headers
+            .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    }
 
     // Write status line without allocation
     socket.write_all(b"HTTP/1.1 ").await?;
@@ -63,8 +80,15 @@ where
     // Ensure all headers are flushed
     socket.flush().await?;
 
-    // Copy the body to the socket
-    tokio::io::copy(&mut body, socket).await?;
+    // Copy the body to the socket, responses to HEAD only carry the headers
+    let head = req_opt.is_some_and(|req| req.method() == Method::HEAD);
+    if !head {
+        if chunked {
+            copy_chunked(&mut body, socket).await?;
+        } else {
+            tokio::io::copy(&mut body, socket).await?;
+        }
+    }
 
     // Ensure all data is flushed
     socket.flush().await?;
@@ -103,7 +127,24 @@ where
     } else {
         info!("Response: {}", response.status().as_u16());
     }
-    let (parts, body) = response.into_parts();
+    let (mut parts, body) = response.into_parts();
+    set_connection_header(&mut parts.headers, req_opt);
+    if let Some(AltSvc(alt_svc)) = req_opt.and_then(|req| req.extensions().get::<AltSvc>()) {
+        parts.headers.insert(ALT_SVC, alt_svc.clone());
+    }
+    // The body is fully buffered, so frame it with its exact length; the client relies on
+    // it to find where the next response on a persistent connection starts
+    let head = req_opt.is_some_and(|req| req.method() == Method::HEAD);
+    if !parts.status.is_informational()
+        && parts.status != StatusCode::NO_CONTENT
+        && parts.status != StatusCode::NOT_MODIFIED
+        && !(head && parts.headers.contains_key(CONTENT_LENGTH))
+    {
+        parts.headers.remove(TRANSFER_ENCODING);
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
 
     // Estimate capacity to reduce reallocations
     let mut resp_bytes = Vec::with_capacity(128 + body.len());
@@ -122,19 +163,59 @@ where
     }
 
     resp_bytes.extend_from_slice(b"\r\n");
-    resp_bytes.extend_from_slice(&body);
+    if !head {
+        resp_bytes.extend_from_slice(&body);
+    }
 
     socket.write_all(&resp_bytes).await?;
 
     Ok(())
 }
 
+/// Copies `body` to `socket` using the chunked transfer coding
+async fn copy_chunked<R, S>(body: &mut R, socket: &mut S) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+    S: AsyncWriteExt + Unpin,
+{
+    let mut buf = vec![0; 16384];
+    loop {
+        let bytes_read = body.read(&mut buf).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        socket
+            .write_all(format!("{:x}\r\n", bytes_read).as_bytes())
+            .await?;
+        socket.write_all(&buf[..bytes_read]).await?;
+        socket.write_all(b"\r\n").await?;
+    }
+    socket.write_all(b"0\r\n\r\n").await
+}
+
+/// Tells the client whether the connection stays open after this response.
+/// Responses sent without a parsed request (e.g. 400) always close it.
+fn set_connection_header(headers: &mut HeaderMap, req_opt: Option<&Request<Vec<u8>>>) {
+    match req_opt {
+        Some(req) if keep_alive(req) => {
+            if req.version() == Version::HTTP_10 {
+                headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+            }
+        }
+        _ => {
+            headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+    }
+}
+
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
 pub fn error_response(status: StatusCode) -> Response<Vec<u8>> {
     let msg = match status {
         StatusCode::BAD_REQUEST => "Bad request",
         StatusCode::FORBIDDEN => "Forbidden",
         StatusCode::NOT_FOUND => "Not found",
+        StatusCode::MISDIRECTED_REQUEST => "Misdirected request",
+        StatusCode::RANGE_NOT_SATISFIABLE => "Range not satisfiable",
         _ => "Unknown error",
     };
 
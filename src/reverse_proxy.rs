@@ -1,60 +1,390 @@
+use crate::encode::Encode;
+use crate::forwarded::{upstream_headers, ForwardedOptions};
+use crate::health::HealthOptions;
+use crate::hop_by_hop::strip_hop_by_hop;
 use crate::matches_pattern;
-use crate::response::{error_response, send_response};
+use crate::request::BodyReader;
+use crate::response::{error_response, send_response, send_response_file};
+use crate::rewrite::{upstream_url, PathRewrite};
+use crate::tunnel::tunnel;
+use crate::upstream::{LbPolicy, Upstream, Upstreams};
 use bytes::Bytes;
-use http::{Request, Response, StatusCode};
-use log::debug;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::TryStreamExt;
+use http::header::{CONNECTION, CONTENT_LENGTH, SET_COOKIE, TRAILER, UPGRADE};
+use http::{HeaderValue, Method, Request, Response, StatusCode, Version};
+use http_body::Frame;
+use log::{debug, error};
+use std::error::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
 use tracing::instrument;
 
+/// Request body chunks buffered between the client connection and the upstream
+const BODY_CHANNEL_CAPACITY: usize = 8;
+
+/// Idle connections kept open to each upstream host
+pub const DEFAULT_POOL_MAX_IDLE: usize = 32;
+/// How long an idle upstream connection is kept before it is closed
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upgraded connections (WebSockets) silent for this long are closed
+pub const DEFAULT_TUNNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Options of a `reverse_proxy` directive: its load balancing and upstream clients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyOptions {
+    pub lb_policy: LbPolicy,
+    /// Other upstreams tried when one can't be reached, all of them by default
+    pub lb_retries: Option<usize>,
+    pub health: HealthOptions,
+    pub forwarded: ForwardedOptions,
+    pub rewrite: PathRewrite,
+    pub tunnel_idle_timeout: Duration,
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Longest wait for the next piece of an upstream response, no limit by default
+    pub read_timeout: Option<Duration>,
+    /// PEM bundle of extra CAs trusted for `https://` upstreams
+    pub tls_ca: Option<String>,
+    /// Accepts any upstream certificate, only meant for development
+    pub tls_insecure_skip_verify: bool,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        ProxyOptions {
+            lb_policy: LbPolicy::RoundRobin,
+            lb_retries: None,
+            health: HealthOptions::default(),
+            forwarded: ForwardedOptions::default(),
+            rewrite: PathRewrite::default(),
+            tunnel_idle_timeout: DEFAULT_TUNNEL_IDLE_TIMEOUT,
+            pool_max_idle: DEFAULT_POOL_MAX_IDLE,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: None,
+            tls_ca: None,
+            tls_insecure_skip_verify: false,
+        }
+    }
+}
+
+/// Builds the client used for all requests to one upstream, so connections, DNS lookups
+/// and TLS sessions are reused. Built once, at config load.
+pub fn build_client(options: &ProxyOptions) -> Result<reqwest::Client, Box<dyn Error>> {
+    let mut builder = reqwest::Client::builder()
+        .pool_max_idle_per_host(options.pool_max_idle)
+        .pool_idle_timeout(options.pool_idle_timeout)
+        .connect_timeout(options.connect_timeout)
+        // Redirects are for the client to follow, not the proxy
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(read_timeout) = options.read_timeout {
+        builder = builder.read_timeout(read_timeout);
+    }
+    if let Some(tls_ca) = &options.tls_ca {
+        let pem = std::fs::read(tls_ca).map_err(|e| format!("Can't read {}: {}", tls_ca, e))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if options.tls_insecure_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(builder.build()?)
+}
+
+#[allow(clippy::too_many_arguments)]
 #[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
 pub async fn directive<S>(
     request: &Request<Vec<u8>>,
+    body: &mut BodyReader<'_>,
     handled: &mut bool,
     socket: &mut S,
     req_opt: Option<&Request<Vec<u8>>>,
-    pattern: &String,
-    destination: &String,
+    pattern: &str,
+    upstreams: &Upstreams,
+    encode: Option<&Encode>,
 ) where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
     if matches_pattern(pattern, request.uri().path()) {
-        let dest_uri = format!("{}{}", destination, request.uri().path());
-        #[cfg(debug_assertions)]
-        debug!("Destination URI: {}", dest_uri);
-        let client = reqwest::Client::new();
-        let mut req_builder = client.request(request.method().clone(), &dest_uri);
+        *handled = true;
+        // A streamed body can't be sent twice, and only idempotent requests are safe to repeat
+        let retryable = body.is_empty() && is_idempotent(request.method());
+        let path = upstreams.rewrite.apply(request.uri().path());
+        let mut tried = Vec::new();
+        loop {
+            let Some(selected) = upstreams.select(request, &tried) else {
+                let status = if tried.is_empty() {
+                    StatusCode::SERVICE_UNAVAILABLE // Every upstream is down
+                } else {
+                    StatusCode::BAD_GATEWAY
+                };
+                let response = error_response(status);
+                let _ = send_response(socket, response, req_opt).await;
+                return;
+            };
+            tried.push(selected.index);
+            let upstream = selected.upstream;
 
-        for (key, value) in request.headers().iter() {
-            req_builder = req_builder.header(key, value);
-        }
-        let body = request.body();
-        if !body.is_empty() {
-            req_builder = req_builder.body(body.clone());
+            match forward(request, body, socket, upstream, &path, &upstreams.forwarded).await {
+                Ok(resp) => {
+                    upstream.record_success();
+                    if resp.status() == StatusCode::SWITCHING_PROTOCOLS
+                        && upgrade_protocol(request).is_some()
+                    {
+                        let idle_timeout = upstreams.tunnel_idle_timeout;
+                        if let Err(err) =
+                            switch_protocols(resp, body, socket, req_opt, idle_timeout).await
+                        {
+                            debug!("Tunnel to {} closed: {}", upstream.url, err);
+                        }
+                        return;
+                    }
+                    let set_cookie = upstreams.sticky_cookie(request, upstream);
+                    if let Err(err) =
+                        send_upstream_response(resp, request, socket, req_opt, set_cookie, encode)
+                            .await
+                    {
+                        debug!("Response from {} broke off: {}", upstream.url, err);
+                        body.close();
+                    }
+                    return;
+                }
+                Err(err) => {
+                    error!("Upstream {} failed: {}", upstream.url, err);
+                    upstream.record_failure(&upstreams.health);
+                    if !retryable || tried.len() >= upstreams.attempts() {
+                        let response = error_response(StatusCode::BAD_GATEWAY);
+                        let _ = send_response(socket, response, req_opt).await;
+                        return;
+                    }
+                }
+            }
         }
+    }
+}
 
-        match req_builder.send().await {
-            Ok(resp) => {
-                let status = resp.status();
-                let headers = resp.headers().clone();
-                let body = resp.bytes().await.unwrap_or_else(|_| Bytes::new());
+/// Sends `request` to `upstream`, for its rewritten `path`, and waits for the head of its response.
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+async fn forward<S>(
+    request: &Request<Vec<u8>>,
+    body: &mut BodyReader<'_>,
+    socket: &mut S,
+    upstream: &Upstream,
+    path: &str,
+    forwarded: &ForwardedOptions,
+) -> reqwest::Result<reqwest::Response>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let dest_uri = upstream_url(&upstream.url, path, request.uri().query());
+    #[cfg(debug_assertions)]
+    debug!("Destination URI: {}", dest_uri);
+    let mut headers = upstream_headers(request, forwarded);
+    if let Some(protocol) = upgrade_protocol(request) {
+        // Hop-by-hop, but the upstream needs them to switch protocols
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, protocol.clone());
+    }
+    let mut req_builder = upstream
+        .client
+        .request(request.method().clone(), &dest_uri)
+        .headers(headers);
 
-                let mut response_builder = Response::builder().status(status);
+    if body.is_empty() {
+        return req_builder.send().await;
+    }
 
-                for (key, value) in headers.iter() {
-                    response_builder = response_builder.header(key, value);
+    // Trailers of a chunked body are only sent on if announced, as hyper wants
+    for trailer in request.headers().get_all(TRAILER) {
+        req_builder = req_builder.header(TRAILER, trailer);
+    }
+    // Stream the request body from the client as the upstream consumes it,
+    // the bounded channel holds the client back when the upstream is slower
+    let (tx, rx) = mpsc::channel(BODY_CHANNEL_CAPACITY);
+    req_builder = req_builder.body(reqwest::Body::wrap(Upload(rx)));
+    let pump = async {
+        loop {
+            match body.read_chunk(&mut *socket).await {
+                Ok(Some(chunk)) => {
+                    if tx.send(Ok(Frame::data(chunk))).await.is_err() {
+                        break; // The upstream stopped reading
+                    }
+                }
+                Ok(None) => {
+                    if !body.trailers().is_empty() {
+                        let _ = tx.send(Ok(Frame::trailers(body.trailers().clone()))).await;
+                    }
+                    break;
                 }
+                Err(err) => {
+                    // Abort the upstream request rather than let it see a short body
+                    let _ = tx.send(Err(err)).await;
+                    break;
+                }
+            }
+        }
+        drop(tx);
+    };
+    let (result, _) = tokio::join!(req_builder.send(), pump);
+    result
+}
 
-                let response = response_builder.body(body.to_vec()).unwrap();
-                let _ = send_response(socket, response, req_opt).await;
-                *handled = true;
-                return;
+/// Request body sent to an upstream, as the client connection delivers it
+struct Upload(mpsc::Receiver<std::io::Result<Frame<Bytes>>>);
+
+impl http_body::Body for Upload {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::io::Result<Frame<Bytes>>>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Protocol the client asks to switch to, e.g. `websocket`
+fn upgrade_protocol(request: &Request<Vec<u8>>) -> Option<&HeaderValue> {
+    let upgrade = request
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if upgrade && request.version() == Version::HTTP_11 {
+        request.headers().get(UPGRADE)
+    } else {
+        None
+    }
+}
+
+/// Completes a protocol switch accepted by the upstream, then relays raw bytes between
+/// the client and the upstream for as long as the connection lives.
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+async fn switch_protocols<S>(
+    resp: reqwest::Response,
+    body: &mut BodyReader<'_>,
+    socket: &mut S,
+    req_opt: Option<&Request<Vec<u8>>>,
+    idle_timeout: Duration,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let mut head = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .body(Vec::new())?;
+    *head.headers_mut() = resp.headers().clone();
+    let protocol = resp.headers().get(UPGRADE).cloned();
+    strip_hop_by_hop(head.headers_mut());
+    head.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    if let Some(protocol) = protocol {
+        head.headers_mut().insert(UPGRADE, protocol);
+    }
+
+    let mut upstream = match resp.upgrade().await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            let response = error_response(StatusCode::BAD_GATEWAY);
+            send_response(socket, response, req_opt).await?;
+            return Err(err.into());
+        }
+    };
+    send_response(socket, head, req_opt).await?;
+
+    // The client may not have waited for the 101 before sending
+    let early = body.take_upgraded();
+    if !early.is_empty() {
+        upstream.write_all(&early).await?;
+    }
+    tunnel(socket, upstream, idle_timeout).await?;
+    Ok(())
+}
+
+/// Methods that can be repeated on another upstream without changing the outcome
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Relays the upstream response to the client as it arrives.
+///
+/// Hop-by-hop headers stay behind and the framing is redone for the client connection:
+/// `Content-Length` when the upstream sent one, otherwise the body is re-chunked. HTTP/1.0
+/// clients don't understand chunks, bodies without a length are buffered for them and
+/// the others aren't compressed on the fly.
+#[cfg_attr(debug_assertions, instrument(level = "trace", skip_all))]
+async fn send_upstream_response<S>(
+    resp: reqwest::Response,
+    request: &Request<Vec<u8>>,
+    socket: &mut S,
+    req_opt: Option<&Request<Vec<u8>>>,
+    set_cookie: Option<String>,
+    encode: Option<&Encode>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let status = resp.status();
+    let mut head = Response::builder().status(status).body(())?;
+    *head.headers_mut() = resp.headers().clone();
+    strip_hop_by_hop(head.headers_mut());
+    if let Some(set_cookie) = set_cookie {
+        head.headers_mut()
+            .append(SET_COOKIE, HeaderValue::from_str(&set_cookie)?);
+    }
+
+    let has_body = request.method() != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED;
+    // The body arrives de-chunked: its length is known only if the upstream framed it with one
+    let length = resp.content_length();
+    if has_body {
+        match length {
+            Some(length) => {
+                head.headers_mut().insert(CONTENT_LENGTH, length.into());
             }
-            Err(_) => {
-                let response = error_response(StatusCode::BAD_GATEWAY);
-                let _ = send_response(socket, response, req_opt).await;
-                *handled = true;
-                return;
+            None => {
+                head.headers_mut().remove(CONTENT_LENGTH);
             }
         }
     }
+
+    let chunking = request.version() == Version::HTTP_11;
+    if !has_body || (length.is_none() && !chunking) {
+        let body = resp.bytes().await?;
+        let mut response = head.map(|_| body.to_vec());
+        if let Some(encode) = encode {
+            encode.encode_response(request, &mut response).await;
+        }
+        return send_response(socket, response, req_opt).await;
+    }
+
+    let reader = StreamReader::new(resp.bytes_stream().map_err(std::io::Error::other));
+    let mut response = head;
+    // Compressed bodies lose their length, only chunks can make up for it
+    let encoding = encode
+        .filter(|_| chunking)
+        .and_then(|e| e.prepare_response(request, &mut response, length));
+    let (parts, _) = response.into_parts();
+    match encoding {
+        Some(encoding) => {
+            let body = encoding.encoder(BufReader::new(reader));
+            send_response_file(socket, Response::from_parts(parts, body), req_opt).await
+        }
+        None => send_response_file(socket, Response::from_parts(parts, reader), req_opt).await,
+    }
 }
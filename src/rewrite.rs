@@ -0,0 +1,114 @@
+use regex::Regex;
+
+/// How a `reverse_proxy` directive maps the request path onto its upstreams.
+#[derive(Debug, Clone, Default)]
+pub struct PathRewrite {
+    /// Leading path segments removed before the request is forwarded, e.g. `/api/v1`
+    pub strip_prefix: Option<String>,
+    /// Regex applied after the prefix is stripped, `$1`-style captures in the replacement
+    pub rewrite: Option<(Regex, String)>,
+}
+
+impl PartialEq for PathRewrite {
+    fn eq(&self, other: &Self) -> bool {
+        let rewrite = |r: &Self| {
+            r.rewrite
+                .as_ref()
+                .map(|(re, to)| (re.as_str().to_string(), to.clone()))
+        };
+        self.strip_prefix == other.strip_prefix && rewrite(self) == rewrite(other)
+    }
+}
+
+impl PathRewrite {
+    /// The path the upstream sees for a request to `path`
+    pub fn apply(&self, path: &str) -> String {
+        let mut path = path.to_string();
+        if let Some(prefix) = &self.strip_prefix {
+            let prefix = prefix.trim_end_matches('/');
+            // Only whole segments: `/api` is stripped from `/api/users`, not from `/apis`
+            if let Some(rest) = path.strip_prefix(prefix) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    path = rest.to_string();
+                }
+            }
+        }
+        if let Some((regex, replacement)) = &self.rewrite {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        path
+    }
+}
+
+/// Joins the upstream URL, which may carry a base path, with the rewritten request path
+/// and the original query string.
+pub fn upstream_url(upstream: &str, path: &str, query: Option<&str>) -> String {
+    let mut url = format!("{}{}", upstream.trim_end_matches('/'), path);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rewrite::{upstream_url, PathRewrite};
+    use regex::Regex;
+    use std::error::Error;
+
+    #[test]
+    fn test_strip_prefix() -> Result<(), Box<dyn Error>> {
+        let rewrite = PathRewrite {
+            strip_prefix: Some("/api/v1/".to_string()),
+            rewrite: None,
+        };
+        assert_eq!(rewrite.apply("/api/v1/users/7"), "/users/7");
+        assert_eq!(rewrite.apply("/api/v1"), "/");
+        assert_eq!(rewrite.apply("/api/v1/"), "/");
+        assert_eq!(rewrite.apply("/api/v10/users"), "/api/v10/users");
+        assert_eq!(rewrite.apply("/other"), "/other");
+        assert_eq!(PathRewrite::default().apply("/a/b"), "/a/b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite() -> Result<(), Box<dyn Error>> {
+        let rewrite = PathRewrite {
+            strip_prefix: Some("/api".to_string()),
+            rewrite: Some((
+                Regex::new(r"^/users/(\d+)$")?,
+                "/v2/accounts/$1/profile".to_string(),
+            )),
+        };
+        assert_eq!(rewrite.apply("/api/users/42"), "/v2/accounts/42/profile");
+        assert_eq!(rewrite.apply("/api/users/me"), "/users/me");
+
+        let rewrite = PathRewrite {
+            strip_prefix: None,
+            rewrite: Some((Regex::new(r"^/old")?, "new".to_string())),
+        };
+        assert_eq!(rewrite.apply("/old/page"), "/new/page");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_url() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            upstream_url("http://svc", "/users", Some("page=2&q=a%20b")),
+            "http://svc/users?page=2&q=a%20b"
+        );
+        assert_eq!(upstream_url("http://svc/", "/", None), "http://svc/");
+        assert_eq!(
+            upstream_url("http://svc/base/", "/users", Some("")),
+            "http://svc/base/users?"
+        );
+
+        Ok(())
+    }
+}
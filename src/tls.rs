@@ -0,0 +1,628 @@
+use crate::acme::{AcmeState, ACME_TLS_ALPN};
+use crate::client_auth::ClientAuth;
+use log::{error, info};
+use rustls::crypto::aws_lc_rs::Ticketer;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{version, ProtocolVersion, ServerConfig, SupportedProtocolVersion};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+pub const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The rustls configs of a TLS listener, replaced as a whole when its certificates change
+/// so every handshake sees either the old or the new ones.
+pub type SharedConfig = Arc<RwLock<Arc<TlsConfigs>>>;
+
+/// Lowest TLS version a listener accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn from_arg(arg: &str) -> Option<TlsVersion> {
+        match arg {
+            "1.2" => Some(TlsVersion::Tls12),
+            "1.3" => Some(TlsVersion::Tls13),
+            _ => None,
+        }
+    }
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+/// ALPN protocols a listener can speak
+pub const ALPN_PROTOCOLS: [&str; 3] = ["h2", "http/1.1", "http/1.0"];
+
+/// `tls_options` of a listener, unset ones keep the rustls defaults. Hosts sharing a port
+/// share its options, the strictest ones win.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsOptions {
+    pub min_version: Option<TlsVersion>,
+    /// rustls names, e.g. `TLS13_AES_256_GCM_SHA384`, in order of preference
+    pub cipher_suites: Option<Vec<String>>,
+    /// e.g. `X25519` or `secp256r1`, in order of preference
+    pub kx_groups: Option<Vec<String>>,
+    /// Offered to clients in this order, `h2` and `http/1.1` by default
+    pub alpn: Option<Vec<String>>,
+    /// Stateless resumption, off by default
+    pub session_tickets: Option<bool>,
+    /// Sessions kept for resumption by id, 0 turns it off
+    pub session_cache: Option<usize>,
+}
+
+impl TlsOptions {
+    pub fn merge(&mut self, other: &TlsOptions) {
+        fn common(a: &mut Option<Vec<String>>, b: &Option<Vec<String>>) {
+            match (a.as_mut(), b) {
+                (Some(a), Some(b)) => a.retain(|v| b.contains(v)),
+                (None, Some(b)) => *a = Some(b.clone()),
+                _ => {}
+            }
+        }
+        self.min_version = self.min_version.max(other.min_version);
+        common(&mut self.cipher_suites, &other.cipher_suites);
+        common(&mut self.kx_groups, &other.kx_groups);
+        common(&mut self.alpn, &other.alpn);
+        self.session_tickets = match (self.session_tickets, other.session_tickets) {
+            (Some(a), Some(b)) => Some(a && b),
+            (a, b) => a.or(b),
+        };
+        self.session_cache = match (self.session_cache, other.session_cache) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// `provider` limited to the configured cipher suites and key exchange groups
+    pub fn provider(&self, provider: &CryptoProvider) -> Result<CryptoProvider, Box<dyn Error>> {
+        let mut provider = provider.clone();
+        if let Some(names) = &self.cipher_suites {
+            provider.cipher_suites = names
+                .iter()
+                .filter_map(|name| {
+                    provider
+                        .cipher_suites
+                        .iter()
+                        .find(|s| s.suite().as_str() == Some(name.as_str()))
+                        .copied()
+                })
+                .collect();
+        }
+        if let Some(names) = &self.kx_groups {
+            provider.kx_groups = names
+                .iter()
+                .filter_map(|name| {
+                    provider
+                        .kx_groups
+                        .iter()
+                        .find(|g| g.name().as_str() == Some(name.as_str()))
+                        .copied()
+                })
+                .collect();
+        }
+        let usable = provider
+            .cipher_suites
+            .iter()
+            .any(|suite| match suite.version().version {
+                ProtocolVersion::TLSv1_3 => true,
+                _ => self.min_version != Some(TlsVersion::Tls13),
+            });
+        if !usable || provider.kx_groups.is_empty() {
+            return Err("No cipher suite or key exchange group left by 'tls_options'".into());
+        }
+        Ok(provider)
+    }
+
+    pub fn versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self.min_version {
+            Some(TlsVersion::Tls13) => TLS13_ONLY,
+            _ => rustls::ALL_VERSIONS,
+        }
+    }
+
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match &self.alpn {
+            Some(protocols) => protocols.iter().map(|p| p.as_bytes().to_vec()).collect(),
+            None => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+
+    /// Sets up session resumption of `config`
+    pub fn apply_sessions(&self, config: &mut ServerConfig) -> Result<(), Box<dyn Error>> {
+        if self.session_tickets == Some(true) {
+            config.ticketer = Ticketer::new()?;
+        }
+        match self.session_cache {
+            Some(0) => config.session_storage = Arc::new(NoServerSessionStorage {}),
+            Some(size) => config.session_storage = ServerSessionMemoryCache::new(size),
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// The rustls name of a cipher suite of the crypto provider, matched case-insensitively
+pub fn cipher_suite_name(name: &str) -> Option<&'static str> {
+    crypto_provider()
+        .cipher_suites
+        .iter()
+        .filter_map(|suite| suite.suite().as_str())
+        .find(|suite| suite.eq_ignore_ascii_case(name))
+}
+
+/// The name of a key exchange group of the crypto provider, matched case-insensitively
+pub fn kx_group_name(name: &str) -> Option<&'static str> {
+    crypto_provider()
+        .kx_groups
+        .iter()
+        .filter_map(|group| group.name().as_str())
+        .find(|group| group.eq_ignore_ascii_case(name))
+}
+
+/// Values looked up by SNI name: those of exact hosts, then those of `*.example.com`
+/// wildcards, which cover a single label.
+#[derive(Debug)]
+struct HostMap<T> {
+    exact: HashMap<String, T>,
+    /// Keyed by the parent domain of `*.example.com` hosts
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> Default for HostMap<T> {
+    fn default() -> Self {
+        HostMap {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+}
+
+impl<T> HostMap<T> {
+    /// `host` is a Cbltfile host, with or without a port
+    fn insert(&mut self, host: &str, value: T) {
+        let host = normalize(host_name(host));
+        match host.strip_prefix("*.") {
+            Some(parent) => self.wildcard.insert(parent.to_string(), value),
+            None => self.exact.insert(host, value),
+        };
+    }
+
+    fn exact(&self, name: &str) -> Option<&T> {
+        self.exact.get(name)
+    }
+
+    fn wildcard(&self, name: &str) -> Option<&T> {
+        name.split_once('.')
+            .filter(|(label, _)| !label.is_empty())
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+    }
+
+    fn get(&self, name: &str) -> Option<&T> {
+        self.exact(name).or_else(|| self.wildcard(name))
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The configs of a TLS listener. Hosts that authenticate clients each get their own, picked
+/// by SNI before the handshake, so only their clients are asked for a certificate.
+#[derive(Debug)]
+pub struct TlsConfigs {
+    default: Arc<ServerConfig>,
+    client_auth: HostMap<(Arc<ServerConfig>, ClientAuth)>,
+}
+
+impl TlsConfigs {
+    pub fn new(default: Arc<ServerConfig>) -> TlsConfigs {
+        TlsConfigs {
+            default,
+            client_auth: HostMap::default(),
+        }
+    }
+
+    pub fn add_client_auth(&mut self, host: &str, config: Arc<ServerConfig>, auth: ClientAuth) {
+        self.client_auth.insert(host, (config, auth));
+    }
+
+    /// The config for a handshake asking for `name`, and how it authenticates the client
+    pub fn select(&self, name: Option<&str>) -> (Arc<ServerConfig>, Option<ClientAuth>) {
+        match name.and_then(|name| self.client_auth.get(&normalize(name))) {
+            Some((config, auth)) => (config.clone(), Some(auth.clone())),
+            None => (self.default.clone(), None),
+        }
+    }
+}
+
+/// Picks the certificate of a TLS listener by the SNI name the client asks for, so every
+/// host sharing the port is served its own.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    /// Certificates of `tls "auto"` hosts, swapped in as they are renewed
+    acme: Arc<AcmeState>,
+    certs: HostMap<Arc<CertifiedKey>>,
+    /// For clients without SNI and names no host matches: the `*` host's certificate,
+    /// else that of the first host in name order
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Loads the certificate and key of every host, `certs` maps host names to their paths.
+    pub fn new(
+        certs: &HashMap<String, (String, String)>,
+        acme: Arc<AcmeState>,
+        provider: &CryptoProvider,
+    ) -> Result<CertResolver, Box<dyn Error>> {
+        let mut resolver = CertResolver {
+            acme,
+            ..CertResolver::default()
+        };
+        let catch_all = certs.keys().any(|h| host_name(h) == "*");
+        let mut hosts: Vec<&String> = certs.keys().collect();
+        hosts.sort();
+        for host in hosts {
+            let (cert, key) = &certs[host];
+            let certified = Arc::new(load_certified_key(cert, key, provider)?);
+            if host_name(host) == "*" {
+                resolver.default = Some(certified);
+                continue;
+            }
+            if resolver.default.is_none() && !catch_all {
+                resolver.default = Some(certified.clone());
+            }
+            resolver.certs.insert(host, certified);
+        }
+        Ok(resolver)
+    }
+
+    /// The certificate for the SNI name `name`
+    pub fn lookup(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = name.map(normalize) else {
+            return self.default.clone();
+        };
+        if let Some(certified) = self.certs.exact(&name) {
+            return Some(certified.clone());
+        }
+        if let Some(certified) = self.acme.certificate(&name) {
+            return Some(certified);
+        }
+        self.certs
+            .wildcard(&name)
+            .or(self.default.as_ref())
+            .cloned()
+            .or_else(|| self.acme.any_certificate())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let validation = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if validation {
+            // A CA checking a TLS-ALPN-01 challenge only accepts the challenge certificate
+            let name = normalize(client_hello.server_name()?);
+            return self.acme.alpn_challenge(&name);
+        }
+        self.lookup(client_hello.server_name())
+    }
+}
+
+fn load_certified_key(
+    cert: &str,
+    key: &str,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", cert).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok(CertifiedKey::from_der(certs, key, provider)?)
+}
+
+/// Rebuilds the config of the listener on `port` with `build` whenever one of the certificate
+/// or key `files` changes on disk, checked each `interval`, or on SIGHUP. A config that fails
+/// to build, e.g. while a new certificate is only half written, leaves the current one in place.
+pub fn spawn_reload<T, F>(
+    port: u16,
+    files: Vec<String>,
+    interval: Duration,
+    build: F,
+    config: Arc<RwLock<Arc<T>>>,
+) where
+    T: Send + Sync + 'static,
+    F: Fn() -> Result<Arc<T>, Box<dyn Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut signal =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+        #[cfg(not(unix))]
+        let mut signal = None;
+        let mut modified = modified_times(&files).await;
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            let forced = tokio::select! {
+                _ = interval.tick() => false,
+                _ = hangup(&mut signal) => true,
+            };
+            let current = modified_times(&files).await;
+            if forced || current != modified {
+                modified = current;
+                reload(port, &build, &config);
+            }
+        }
+    });
+}
+
+fn reload<T, F>(port: u16, build: &F, config: &RwLock<Arc<T>>)
+where
+    F: Fn() -> Result<Arc<T>, Box<dyn Error>>,
+{
+    match build() {
+        Ok(new) => {
+            *config.write().unwrap() = new;
+            info!("Reloaded the TLS certificates of port {}", port);
+        }
+        Err(err) => {
+            error!(
+                "Keeping the current TLS certificates of port {}: {}",
+                port, err
+            );
+        }
+    }
+}
+
+async fn modified_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(files.len());
+    for file in files {
+        let modified = tokio::fs::metadata(file).await.and_then(|m| m.modified());
+        times.push(modified.ok());
+    }
+    times
+}
+
+/// Resolves on each SIGHUP, never if the signal can't be listened to
+#[cfg(unix)]
+async fn hangup(signal: &mut Option<tokio::signal::unix::Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn hangup(_signal: &mut Option<()>) {
+    std::future::pending().await
+}
+
+/// The provider rustls was set up with, the one of its default features otherwise
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// The name part of a Cbltfile host or a `Host` header, without the port
+pub fn host_name(host: &str) -> &str {
+    match host.split_once(':') {
+        Some((name, _)) => name,
+        None => host,
+    }
+}
+
+/// Whether the `*.example.com` style `pattern` covers `name`, which must be a direct
+/// subdomain of it
+pub fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    match (pattern.strip_prefix("*."), name.split_once('.')) {
+        (Some(parent), Some((label, rest))) => {
+            !label.is_empty() && rest.eq_ignore_ascii_case(parent)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{
+        crypto_provider, host_name, spawn_reload, wildcard_matches, CertResolver, TlsOptions,
+        TlsVersion,
+    };
+    use rustls::pki_types::CertificateDer;
+    use rustls::ServerConfig;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    /// Writes a self-signed certificate for `name` and returns its paths and DER form
+    fn self_signed(
+        dir: &std::path::Path,
+        name: &str,
+    ) -> Result<((String, String), CertificateDer<'static>), Box<dyn Error>> {
+        let certified = rcgen::generate_simple_self_signed(vec![name.to_string()])?;
+        let file = name.replace('*', "wildcard");
+        let cert = dir.join(format!("{}.crt", file));
+        let key = dir.join(format!("{}.key", file));
+        std::fs::write(&cert, certified.cert.pem())?;
+        std::fs::write(&key, certified.signing_key.serialize_pem())?;
+        let paths = (
+            cert.to_string_lossy().into_owned(),
+            key.to_string_lossy().into_owned(),
+        );
+        Ok((paths, certified.cert.der().clone()))
+    }
+
+    #[test]
+    fn test_resolver() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let provider = crypto_provider();
+
+        let (a_paths, a) = self_signed(&dir, "a.example.com")?;
+        let (wild_paths, wild) = self_signed(&dir, "*.example.com")?;
+        let (other_paths, other) = self_signed(&dir, "other.org")?;
+        let mut certs = HashMap::new();
+        certs.insert("a.example.com".to_string(), a_paths);
+        certs.insert("*.example.com:443".to_string(), wild_paths);
+        certs.insert("other.org".to_string(), other_paths.clone());
+        let resolver = CertResolver::new(&certs, Default::default(), &provider)?;
+
+        let served = |name: Option<&str>| resolver.lookup(name).map(|c| c.cert[0].clone());
+        assert_eq!(served(Some("a.example.com")), Some(a.clone()));
+        assert_eq!(served(Some("A.Example.COM.")), Some(a.clone()));
+        assert_eq!(served(Some("b.example.com")), Some(wild.clone()));
+        assert_eq!(served(Some("other.org")), Some(other.clone()));
+        // Deeper names aren't covered by the wildcard, they get the default, which is the
+        // wildcard host's certificate here only because it sorts first
+        assert_eq!(served(Some("x.b.example.com")), Some(wild.clone()));
+        assert_eq!(served(None), Some(wild));
+
+        // The catch-all host is the default when there is one
+        let (any_paths, any) = self_signed(&dir, "localhost")?;
+        certs.insert("*".to_string(), any_paths);
+        let resolver = CertResolver::new(&certs, Default::default(), &provider)?;
+        assert_eq!(
+            resolver
+                .lookup(Some("unknown.net"))
+                .map(|c| c.cert[0].clone()),
+            Some(any)
+        );
+        assert_eq!(
+            resolver
+                .lookup(Some("other.org"))
+                .map(|c| c.cert[0].clone()),
+            Some(other)
+        );
+
+        let mut broken = HashMap::new();
+        broken.insert(
+            "broken.org".to_string(),
+            (other_paths.1.clone(), other_paths.1),
+        );
+        assert!(CertResolver::new(&broken, Default::default(), &provider).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let ((cert, key), _) = self_signed(&dir, "reload.test")?;
+        let ((new_cert, new_key), _) = self_signed(&dir, "rotated.test")?;
+        let mut certs = HashMap::new();
+        certs.insert("reload.test".to_string(), (cert.clone(), key.clone()));
+        let build = move || -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+            let resolver = CertResolver::new(&certs, Default::default(), &crypto_provider())?;
+            let config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(resolver));
+            Ok(Arc::new(config))
+        };
+        let config = Arc::new(RwLock::new(build()?));
+        let first = config.read().unwrap().clone();
+        spawn_reload(
+            0,
+            vec![cert.clone(), key.clone()],
+            Duration::from_millis(20),
+            build,
+            config.clone(),
+        );
+        let wait = || tokio::time::sleep(Duration::from_millis(200));
+
+        // Half way through a rotation the key doesn't match the certificate yet
+        std::fs::copy(&new_cert, &cert)?;
+        wait().await;
+        assert!(Arc::ptr_eq(&config.read().unwrap(), &first));
+
+        std::fs::copy(&new_key, &key)?;
+        wait().await;
+        let second = config.read().unwrap().clone();
+        assert!(!Arc::ptr_eq(&second, &first));
+
+        // Untouched files don't cause reloads
+        wait().await;
+        assert!(Arc::ptr_eq(&config.read().unwrap(), &second));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_matching() -> Result<(), Box<dyn Error>> {
+        assert_eq!(host_name("example.com:8443"), "example.com");
+        assert_eq!(host_name("example.com"), "example.com");
+        assert!(wildcard_matches("*.example.com", "a.example.com"));
+        assert!(wildcard_matches("*.example.com", "A.EXAMPLE.com"));
+        assert!(!wildcard_matches("*.example.com", "example.com"));
+        assert!(!wildcard_matches("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_matches("*.example.com", ".example.com"));
+        assert!(!wildcard_matches("*", "example.com"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_options() -> Result<(), Box<dyn Error>> {
+        let names = |names: &[&str]| Some(names.iter().map(|n| n.to_string()).collect());
+        let mut options = TlsOptions {
+            cipher_suites: names(&[
+                "TLS13_AES_128_GCM_SHA256",
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+            ]),
+            session_tickets: Some(true),
+            session_cache: Some(1024),
+            ..Default::default()
+        };
+        // The strictest options of the hosts sharing a listener win
+        options.merge(&TlsOptions {
+            min_version: Some(TlsVersion::Tls13),
+            cipher_suites: names(&["TLS13_AES_128_GCM_SHA256", "TLS13_AES_256_GCM_SHA384"]),
+            kx_groups: names(&["X25519"]),
+            session_tickets: Some(false),
+            session_cache: Some(64),
+            ..Default::default()
+        });
+        assert_eq!(options.min_version, Some(TlsVersion::Tls13));
+        assert_eq!(options.cipher_suites, names(&["TLS13_AES_128_GCM_SHA256"]));
+        assert_eq!(options.kx_groups, names(&["X25519"]));
+        assert_eq!(options.alpn, None);
+        assert_eq!(
+            options.alpn_protocols(),
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        assert_eq!(options.session_tickets, Some(false));
+        assert_eq!(options.session_cache, Some(64));
+        assert_eq!(options.versions().len(), 1);
+
+        let provider = options.provider(&crypto_provider())?;
+        assert_eq!(provider.cipher_suites.len(), 1);
+        assert_eq!(provider.kx_groups.len(), 1);
+
+        // Only TLS 1.2 suites left while TLS 1.3 is required
+        let options = TlsOptions {
+            min_version: Some(TlsVersion::Tls13),
+            cipher_suites: names(&["TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"]),
+            ..Default::default()
+        };
+        assert!(options.provider(&crypto_provider()).is_err());
+
+        Ok(())
+    }
+}
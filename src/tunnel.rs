@@ -0,0 +1,100 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Relays bytes both ways between `a` and `b` until both sides are done or neither
+/// has sent anything for `idle_timeout`. When one side finishes sending, the other is
+/// told with a write shutdown and may still answer.
+pub async fn tunnel<A, B>(a: A, b: B, idle_timeout: Duration) -> std::io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let mut a_buf = vec![0; 16 * 1024];
+    let mut b_buf = vec![0; 16 * 1024];
+    let mut a_open = true;
+    let mut b_open = true;
+
+    while a_open || b_open {
+        tokio::select! {
+            read = a_read.read(&mut a_buf), if a_open => {
+                let n = read?;
+                if n == 0 {
+                    a_open = false;
+                    b_write.shutdown().await?;
+                } else {
+                    b_write.write_all(&a_buf[..n]).await?;
+                    b_write.flush().await?;
+                }
+            }
+            read = b_read.read(&mut b_buf), if b_open => {
+                let n = read?;
+                if n == 0 {
+                    b_open = false;
+                    a_write.shutdown().await?;
+                } else {
+                    a_write.write_all(&b_buf[..n]).await?;
+                    a_write.flush().await?;
+                }
+            }
+            _ = tokio::time::sleep(idle_timeout) => {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tunnel::tunnel;
+    use std::error::Error;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tunnel() -> Result<(), Box<dyn Error>> {
+        let (mut client, proxy_client) = duplex(64);
+        let (proxy_upstream, mut upstream) = duplex(64);
+        let relay = tokio::spawn(tunnel(proxy_client, proxy_upstream, Duration::from_secs(5)));
+
+        client.write_all(b"ping").await?;
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        // More than the duplex capacity, flows through as the other side reads
+        let big = vec![7u8; 10_000];
+        let writer = tokio::spawn(async move {
+            upstream.write_all(&big).await.unwrap();
+            upstream.shutdown().await.unwrap();
+            upstream
+        });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await?;
+        assert_eq!(received.len(), 10_000);
+
+        // The client may still talk after the upstream finished sending
+        client.write_all(b"bye").await?;
+        let mut upstream = writer.await?;
+        let mut buf = [0; 3];
+        upstream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"bye");
+
+        client.shutdown().await?;
+        relay.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result<(), Box<dyn Error>> {
+        let (_client, proxy_client) = duplex(64);
+        let (proxy_upstream, _upstream) = duplex(64);
+        let result = tunnel(proxy_client, proxy_upstream, Duration::from_millis(50)).await;
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+use crate::forwarded::ForwardedOptions;
+use crate::health::HealthOptions;
+use crate::reverse_proxy::{build_client, ProxyOptions};
+use crate::rewrite::PathRewrite;
+use http::header::COOKIE;
+use http::Request;
+use log::error;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Cookie remembering the upstream of a client with `lb_policy "cookie"`
+pub const DEFAULT_LB_COOKIE: &str = "cblt_upstream";
+
+/// How a `reverse_proxy` directive spreads requests over its upstreams.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LbPolicy {
+    RoundRobin,
+    /// The upstream with the fewest requests in flight
+    LeastConn,
+    Random,
+    /// Hash of the client IP, so a client keeps hitting the same upstream
+    IpHash,
+    /// Sticky sessions: the upstream is remembered in a cookie of this name
+    Cookie {
+        name: String,
+    },
+}
+
+impl LbPolicy {
+    /// Parses the arguments of `lb_policy`, e.g. `"ip_hash"` or `"cookie" "srv"`
+    pub fn from_args(args: &[&str]) -> Option<LbPolicy> {
+        match args {
+            ["round_robin"] => Some(LbPolicy::RoundRobin),
+            ["least_conn"] => Some(LbPolicy::LeastConn),
+            ["random"] => Some(LbPolicy::Random),
+            ["ip_hash"] => Some(LbPolicy::IpHash),
+            ["cookie"] => Some(LbPolicy::Cookie {
+                name: DEFAULT_LB_COOKIE.to_string(),
+            }),
+            ["cookie", name] => Some(LbPolicy::Cookie {
+                name: name.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub url: String,
+    pub client: reqwest::Client,
+    id: String,          // Value of the sticky cookie, stable across restarts
+    active: AtomicUsize, // Requests in flight
+    healthy: AtomicBool, // Verdict of the active health checks
+    fails: AtomicUsize,  // Failed requests in a row, for the passive health checks
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Whether requests may be sent to this upstream: it passes the active health checks
+    /// and isn't ejected by the passive ones
+    pub fn is_available(&self) -> bool {
+        if !self.is_healthy() {
+            return false;
+        }
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                *ejected_until = None;
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Passive health check: a request to this upstream failed
+    pub fn record_failure(&self, health: &HealthOptions) {
+        let Some(fail_duration) = health.fail_duration else {
+            return;
+        };
+        let fails = self.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= health.max_fails {
+            error!(
+                "Upstream {} failed {} times in a row, ejected for {:?}",
+                self.url, fails, fail_duration
+            );
+            self.fails.store(0, Ordering::Relaxed);
+            *self.ejected_until.lock().unwrap() = Some(Instant::now() + fail_duration);
+        }
+    }
+
+    /// Passive health check: the upstream answered
+    pub fn record_success(&self) {
+        self.fails.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Upstreams of a `reverse_proxy` directive and the state of its load balancer,
+/// shared by all connections.
+#[derive(Debug)]
+pub struct Upstreams {
+    pub list: Vec<Upstream>,
+    pub health: HealthOptions,
+    pub forwarded: ForwardedOptions,
+    pub rewrite: PathRewrite,
+    pub tunnel_idle_timeout: Duration,
+    policy: LbPolicy,
+    retries: usize,
+    next: AtomicUsize, // Round-robin position
+}
+
+/// An upstream picked for a request, counted as in flight until dropped.
+#[derive(Debug)]
+pub struct Selected<'a> {
+    pub upstream: &'a Upstream,
+    pub index: usize,
+}
+
+impl Drop for Selected<'_> {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Upstreams {
+    pub fn new(urls: &[&str], options: &ProxyOptions) -> Result<Self, Box<dyn Error>> {
+        let mut list = Vec::with_capacity(urls.len());
+        for url in urls {
+            list.push(Upstream {
+                url: url.to_string(),
+                client: build_client(options)?,
+                id: format!("{:016x}", fnv1a(url.as_bytes())),
+                active: AtomicUsize::new(0),
+                healthy: AtomicBool::new(true),
+                fails: AtomicUsize::new(0),
+                ejected_until: Mutex::new(None),
+            });
+        }
+        Ok(Upstreams {
+            list,
+            health: options.health.clone(),
+            forwarded: options.forwarded.clone(),
+            rewrite: options.rewrite.clone(),
+            tunnel_idle_timeout: options.tunnel_idle_timeout,
+            policy: options.lb_policy.clone(),
+            // By default every upstream gets a chance
+            retries: options.lb_retries.unwrap_or(usize::MAX),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// How many upstreams a request may be sent to: the first one plus the retries
+    pub fn attempts(&self) -> usize {
+        self.retries.saturating_add(1).min(self.list.len())
+    }
+
+    /// Picks the upstream for `request` among the available ones not `tried` yet.
+    pub fn select<B>(&self, request: &Request<B>, tried: &[usize]) -> Option<Selected<'_>> {
+        let n = self.list.len();
+        let usable = |i: &usize| !tried.contains(i) && self.list[*i].is_available();
+        let untried = |start: usize| (0..n).map(move |k| (start % n + k) % n).find(usable);
+        let index = match &self.policy {
+            LbPolicy::RoundRobin => untried(self.next.fetch_add(1, Ordering::Relaxed)),
+            LbPolicy::Random => untried(fastrand::usize(..n)),
+            LbPolicy::IpHash => match request.extensions().get::<SocketAddr>() {
+                Some(peer) => untried(ip_hash(peer.ip()) as usize),
+                None => untried(0),
+            },
+            LbPolicy::LeastConn => {
+                // Ties go round-robin so idle upstreams share the load
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..n)
+                    .map(|k| (start % n + k) % n)
+                    .filter(usable)
+                    .min_by_key(|&i| self.list[i].active.load(Ordering::Relaxed))
+            }
+            LbPolicy::Cookie { name } => {
+                let pinned = cookie(request, name)
+                    .and_then(|id| self.list.iter().position(|u| u.id == id).filter(usable));
+                pinned.or_else(|| untried(self.next.fetch_add(1, Ordering::Relaxed)))
+            }
+        }?;
+        let upstream = &self.list[index];
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        Some(Selected { upstream, index })
+    }
+
+    /// `Set-Cookie` value pinning the client to `upstream`, unless it is pinned to it already
+    pub fn sticky_cookie<B>(&self, request: &Request<B>, upstream: &Upstream) -> Option<String> {
+        match &self.policy {
+            LbPolicy::Cookie { name } if cookie(request, name) != Some(&upstream.id) => {
+                Some(format!("{}={}; Path=/; HttpOnly", name, upstream.id))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Value of the request cookie `name`
+fn cookie<'a, B>(request: &'a Request<B>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| match pair.trim().split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            _ => None,
+        })
+}
+
+fn ip_hash(ip: IpAddr) -> u64 {
+    match ip {
+        IpAddr::V4(ip) => fnv1a(&ip.octets()),
+        IpAddr::V6(ip) => fnv1a(&ip.octets()),
+    }
+}
+
+/// FNV-1a, unlike the std hasher its output doesn't change between builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::HealthOptions;
+    use crate::reverse_proxy::ProxyOptions;
+    use crate::upstream::{LbPolicy, Upstreams};
+    use http::Request;
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn upstreams(policy: LbPolicy) -> Result<Upstreams, Box<dyn Error>> {
+        let options = ProxyOptions {
+            lb_policy: policy,
+            ..ProxyOptions::default()
+        };
+        Upstreams::new(&["http://a:80", "http://b:80", "http://c:80"], &options)
+    }
+
+    fn request(peer: &str, cookie: Option<&str>) -> Request<Vec<u8>> {
+        let mut builder = Request::builder().uri("/");
+        if let Some(cookie) = cookie {
+            builder = builder.header("Cookie", cookie);
+        }
+        let mut request = builder.body(Vec::new()).unwrap();
+        request
+            .extensions_mut()
+            .insert(peer.parse::<SocketAddr>().unwrap());
+        request
+    }
+
+    #[test]
+    fn test_round_robin() -> Result<(), Box<dyn Error>> {
+        let upstreams = upstreams(LbPolicy::RoundRobin)?;
+        let req = request("10.0.0.1:1000", None);
+        let picks: Vec<usize> = (0..4)
+            .map(|_| upstreams.select(&req, &[]).unwrap().index)
+            .collect();
+        assert_eq!(picks, vec![0, 1, 2, 0]);
+
+        // Retries skip the upstreams already tried
+        assert_eq!(upstreams.select(&req, &[1, 2]).unwrap().index, 0);
+        assert!(upstreams.select(&req, &[0, 1, 2]).is_none());
+        assert_eq!(upstreams.attempts(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_health() -> Result<(), Box<dyn Error>> {
+        let options = ProxyOptions {
+            health: HealthOptions {
+                max_fails: 2,
+                fail_duration: Some(Duration::from_millis(50)),
+                ..HealthOptions::default()
+            },
+            ..ProxyOptions::default()
+        };
+        let upstreams = Upstreams::new(&["http://a:80", "http://b:80"], &options)?;
+        let req = request("10.0.0.1:1000", None);
+
+        // Down according to the active checks
+        upstreams.list[0].set_healthy(false);
+        for _ in 0..3 {
+            assert_eq!(upstreams.select(&req, &[]).unwrap().index, 1);
+        }
+        upstreams.list[0].set_healthy(true);
+
+        // Ejected by the passive checks after two failures in a row, until the cooldown ends
+        upstreams.list[1].record_failure(&upstreams.health);
+        upstreams.list[1].record_success();
+        upstreams.list[1].record_failure(&upstreams.health);
+        assert!(upstreams.list[1].is_available());
+        upstreams.list[1].record_failure(&upstreams.health);
+        assert!(!upstreams.list[1].is_available());
+        for _ in 0..3 {
+            assert_eq!(upstreams.select(&req, &[]).unwrap().index, 0);
+        }
+        assert!(upstreams.select(&req, &[0]).is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(upstreams.list[1].is_available());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_conn() -> Result<(), Box<dyn Error>> {
+        let upstreams = upstreams(LbPolicy::LeastConn)?;
+        let req = request("10.0.0.1:1000", None);
+        let first = upstreams.select(&req, &[]).unwrap();
+        let second = upstreams.select(&req, &[]).unwrap();
+        let third = upstreams.select(&req, &[]).unwrap();
+        assert_ne!(first.index, second.index);
+        assert_ne!(second.index, third.index);
+        assert_ne!(first.index, third.index);
+
+        let busy = second.index;
+        drop(first);
+        drop(third);
+        for _ in 0..5 {
+            assert_ne!(upstreams.select(&req, &[]).unwrap().index, busy);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ip_hash() -> Result<(), Box<dyn Error>> {
+        let upstreams = upstreams(LbPolicy::IpHash)?;
+        let client = request("192.168.1.7:5000", None);
+        let index = upstreams.select(&client, &[]).unwrap().index;
+        for port in [5001, 6000] {
+            let same_ip = request(&format!("192.168.1.7:{}", port), None);
+            assert_eq!(upstreams.select(&same_ip, &[]).unwrap().index, index);
+        }
+        assert_ne!(upstreams.select(&client, &[index]).unwrap().index, index);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cookie() -> Result<(), Box<dyn Error>> {
+        let upstreams = upstreams(LbPolicy::from_args(&["cookie", "srv"]).unwrap())?;
+        let req = request("10.0.0.1:1000", None);
+        let selected = upstreams.select(&req, &[]).unwrap();
+        let set_cookie = upstreams.sticky_cookie(&req, selected.upstream).unwrap();
+        assert!(set_cookie.starts_with("srv="));
+
+        let pair = set_cookie.split(';').next().unwrap();
+        let sticky = request("10.0.0.2:1000", Some(&format!("a=1; {}", pair)));
+        for _ in 0..3 {
+            let again = upstreams.select(&sticky, &[]).unwrap();
+            assert_eq!(again.index, selected.index);
+            assert_eq!(upstreams.sticky_cookie(&sticky, again.upstream), None);
+        }
+        // The pinned upstream failed: another one takes over
+        let retry = upstreams.select(&sticky, &[selected.index]).unwrap();
+        assert_ne!(retry.index, selected.index);
+        assert!(upstreams.sticky_cookie(&sticky, retry.upstream).is_some());
+
+        assert_eq!(LbPolicy::from_args(&["sticky"]), None);
+
+        Ok(())
+    }
+}